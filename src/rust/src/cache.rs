@@ -0,0 +1,333 @@
+use crate::connection::Direction;
+use crate::utils::{parse_datetime, EkError, ErrorKind};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+/// One cached response as written to disk
+#[derive(Serialize, Deserialize)]
+struct Record {
+    direction: String,
+    payload: Value,
+    /// Unix seconds
+    created: i64,
+    /// Unix seconds, `None` for historical data that never expires
+    expires: Option<i64>,
+    response: Value,
+}
+
+/// Summary of a cached response, without the response itself
+pub struct CacheEntry {
+    pub key: String,
+    pub direction: String,
+    pub created: DateTime<Utc>,
+    pub expires: Option<DateTime<Utc>>,
+    pub bytes: u64,
+}
+
+/// On-disk cache of raw responses, one file per (direction, payload). Responses covering a
+/// range that ended before today are kept forever, everything else for `ttl`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseCache {
+    pub dir: PathBuf,
+    pub ttl: Duration,
+}
+
+impl ResponseCache {
+    pub fn new(dir: PathBuf, ttl: Duration) -> Self {
+        Self { dir, ttl }
+    }
+
+    fn stored() -> &'static Mutex<Option<ResponseCache>> {
+        static CACHE: OnceLock<Mutex<Option<ResponseCache>>> = OnceLock::new();
+        CACHE.get_or_init(|| Mutex::new(None))
+    }
+
+    /// Cache used by new connections, `None` while caching is disabled
+    pub fn global() -> Option<ResponseCache> {
+        ResponseCache::stored()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn set_global(cache: Option<ResponseCache>) {
+        *ResponseCache::stored()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = cache;
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+
+    /// Cached response to `payload` if there is one that has not expired
+    pub fn get(&self, direction: Direction, payload: &Value) -> Option<Value> {
+        let record = read_record(&self.path(&key(direction, payload)))?;
+        match record.expires {
+            Some(t) if t <= Utc::now().timestamp() => None,
+            _ => Some(record.response),
+        }
+    }
+
+    /// Stores `response`. The cache only saves requests, so failing to write it is not an error
+    pub fn put(&self, direction: Direction, payload: &Value, response: &Value) {
+        let now = Utc::now();
+        let expires = match historical(direction, payload, now.date_naive()) {
+            true => None,
+            false => Some(now.timestamp() + self.ttl.as_secs() as i64),
+        };
+        let record = Record {
+            direction: direction.to_string(),
+            payload: payload.clone(),
+            created: now.timestamp(),
+            expires,
+            response: response.clone(),
+        };
+        write_record(&self.dir, &self.path(&key(direction, payload)), &record);
+    }
+
+    /// Every response in the cache, oldest first
+    pub fn entries(&self) -> Vec<CacheEntry> {
+        let mut entries: Vec<CacheEntry> = self
+            .files()
+            .into_iter()
+            .filter_map(|path| {
+                let bytes = fs::metadata(&path).ok()?.len();
+                let record = read_record(&path)?;
+                Some(CacheEntry {
+                    key: path.file_stem()?.to_string_lossy().into_owned(),
+                    direction: record.direction,
+                    created: DateTime::from_timestamp(record.created, 0)?,
+                    expires: record.expires.and_then(|t| DateTime::from_timestamp(t, 0)),
+                    bytes,
+                })
+            })
+            .collect();
+        entries.sort_by_key(|e| e.created);
+        entries
+    }
+
+    /// Removes every response, or only the expired ones, and returns how many were removed
+    pub fn clear(&self, expired_only: bool) -> Result<usize, EkError> {
+        let now = Utc::now().timestamp();
+        let mut removed = 0;
+        for path in self.files() {
+            if expired_only && !expired(&path, now) {
+                continue;
+            }
+            fs::remove_file(&path).map_err(|e| {
+                let msg = format!("Could not remove {}: {e}", path.display());
+                EkError::new(ErrorKind::Error, msg)
+            })?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    fn files(&self) -> Vec<PathBuf> {
+        let Ok(dir) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        dir.filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|e| e == "json"))
+            .collect()
+    }
+}
+
+/// Responses of a single long request, written as each chunk arrives and kept until the
+/// directory is removed. Running the same request again only sends the chunks not in it
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    pub dir: PathBuf,
+}
+
+impl Checkpoint {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+
+    pub fn get(&self, direction: Direction, payload: &Value) -> Option<Value> {
+        read_record(&self.path(&key(direction, payload))).map(|r| r.response)
+    }
+
+    /// Stores `response`, a chunk that could not be saved is just downloaded again next time
+    pub fn put(&self, direction: Direction, payload: &Value, response: &Value) {
+        let record = Record {
+            direction: direction.to_string(),
+            payload: payload.clone(),
+            created: Utc::now().timestamp(),
+            expires: None,
+            response: response.clone(),
+        };
+        write_record(&self.dir, &self.path(&key(direction, payload)), &record);
+    }
+}
+
+/// Hex SHA-256 of the direction and payload, used by the cache and checkpoints. The payload is
+/// hashed in canonical form, so equal payloads give equal keys whatever order their object keys
+/// are kept in
+fn key(direction: Direction, payload: &Value) -> String {
+    let mut canonical = String::new();
+    write_canonical(payload, &mut canonical);
+    let mut hasher = Sha256::new();
+    hasher.update(direction.to_string().as_bytes());
+    hasher.update(b"\n");
+    hasher.update(canonical.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Compact JSON with the keys of every object sorted, independent of serde_json's
+/// `preserve_order` feature
+fn write_canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            out.push('{');
+            for (i, (k, v)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(k.clone()).to_string());
+                out.push(':');
+                write_canonical(v, out);
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, v) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(v, out);
+            }
+            out.push(']');
+        }
+        other => out.push_str(&other.to_string()),
+    }
+}
+
+fn read_record(path: &Path) -> Option<Record> {
+    let text = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&text).ok()
+}
+
+fn write_record(dir: &Path, path: &Path, record: &Record) {
+    let Ok(text) = serde_json::to_string(record) else {
+        return;
+    };
+    // Written next to the target first so a concurrent reader never sees half a file
+    let tmp = path.with_extension("tmp");
+    if fs::create_dir_all(dir).is_ok() && fs::write(&tmp, text).is_ok() {
+        let _ = fs::rename(&tmp, path);
+    }
+}
+
+fn expired(path: &Path, now: i64) -> bool {
+    match read_record(path) {
+        Some(r) => r.expires.is_some_and(|t| t <= now),
+        // Unreadable files are of no use either
+        None => true,
+    }
+}
+
+/// Whether `payload` asks for a range that ended before `today`, the answer to which does not
+/// change anymore. Datagrid requests count only when every field has an explicit past EDate
+fn historical(direction: Direction, payload: &Value, today: NaiveDate) -> bool {
+    let past = |d: Option<&str>| {
+        d.and_then(|d| {
+            NaiveDate::parse_from_str(d, "%Y-%m-%d")
+                .ok()
+                .or_else(|| parse_datetime(d).map(|t| t.date()))
+        })
+        .is_some_and(|d| d < today)
+    };
+    match direction {
+        Direction::Datagrid => {
+            let request = &payload["requests"][0];
+            let e_date = request["parameters"]["EDate"].as_str();
+            let fields = request["fields"].as_array().cloned().unwrap_or_default();
+            !fields.is_empty()
+                && fields
+                    .iter()
+                    .all(|f| past(f["parameters"]["EDate"].as_str().or(e_date)))
+        }
+        Direction::TimeSeries => past(payload["enddate"].as_str()),
+        Direction::NewsHeadlines => past(payload["dateTo"].as_str()),
+        Direction::NewsStory | Direction::SymbologySearch => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_historical() {
+        let today = NaiveDate::from_ymd_opt(2024, 6, 1).unwrap();
+        let dg = |e_date: &str| {
+            json!({"requests": [{
+                "instruments": ["IBM.N"],
+                "fields": [{"name": "TR.CLOSE"}, {"name": "TR.OPEN", "parameters": {"EDate": "2024-06-01"}}],
+                "parameters": {"SDate": "2020-01-01", "EDate": e_date}
+            }]})
+        };
+        // TR.OPEN overrides the past request EDate with today
+        assert!(!historical(Direction::Datagrid, &dg("2021-01-01"), today));
+        let mut payload = dg("2021-01-01");
+        payload["requests"][0]["fields"][1]["parameters"]["EDate"] = json!("2022-01-01");
+        assert!(historical(Direction::Datagrid, &payload, today));
+        assert!(!historical(Direction::Datagrid, &dg("-1Y"), today));
+
+        let ts = json!({"rics": ["IBM.N"], "enddate": "2024-05-31 23:59:00"});
+        assert!(historical(Direction::TimeSeries, &ts, today));
+        let ts = json!({"rics": ["IBM.N"], "enddate": "2024-06-01 00:00:00"});
+        assert!(!historical(Direction::TimeSeries, &ts, today));
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("ek_checkpoint_{}", std::process::id()));
+        let checkpoint = Checkpoint::new(dir.clone());
+        let payload = json!({"rics": ["IBM.N"], "enddate": "2024-05-31 00:00:00"});
+        let response = json!({"timeseriesData": []});
+        assert_eq!(checkpoint.get(Direction::TimeSeries, &payload), None);
+        checkpoint.put(Direction::TimeSeries, &payload, &response);
+        assert_eq!(
+            checkpoint.get(Direction::TimeSeries, &payload),
+            Some(response)
+        );
+        assert_eq!(checkpoint.get(Direction::Datagrid, &payload), None);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_key() {
+        let a = json!({"rics": ["IBM.N"], "interval": "daily"});
+        let b = json!({"interval": "daily", "rics": ["IBM.N"]});
+        assert_eq!(
+            key(Direction::TimeSeries, &a),
+            key(Direction::TimeSeries, &b)
+        );
+        assert_ne!(key(Direction::TimeSeries, &a), key(Direction::Datagrid, &a));
+
+        let mut canonical = String::new();
+        write_canonical(
+            &json!({"b": [{"d": 1, "c": null}], "a": "x"}),
+            &mut canonical,
+        );
+        assert_eq!(canonical, r#"{"a":"x","b":[{"c":null,"d":1}]}"#);
+    }
+}
@@ -0,0 +1,489 @@
+use crate::connection::{Connection, Direction};
+use crate::utils::{Column, ColumnType, EkError, EkResults, ErrorKind};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+/// Request parameters every TR field accepts, other parameters are reported as unknown until a
+/// field has been downloaded with them
+const COMMON_PARAMETERS: [&str; 22] = [
+    "SDate",
+    "EDate",
+    "Frq",
+    "Period",
+    "Curn",
+    "Scale",
+    "RH",
+    "CH",
+    "IC",
+    "Fill",
+    "Methodology",
+    "Transpose",
+    "Precision",
+    "ReportingState",
+    "ReportType",
+    "Sort",
+    "Source",
+    "Consolidation",
+    "Window",
+    "Points",
+    "AlignType",
+    "NULL",
+];
+
+/// Fragments of the error Eikon gives for a field it cannot resolve, as opposed to a field
+/// without data for the instrument
+const UNKNOWN_FIELD_MESSAGES: [&str; 3] =
+    ["unable to resolve", "unknown field", "formula must contain"];
+
+/// Definition of one field as seen in a response
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldInfo {
+    pub name: String,
+    pub display_name: String,
+    /// Type of the probe value, unknown when the probe instrument had no data for the field
+    pub data_type: Option<ColumnType>,
+    /// Parameters the field has been downloaded with successfully
+    pub parameters: Vec<String>,
+}
+
+/// Field definitions looked up so far, keyed by upper-case field name. With a cache directory
+/// set the catalogue is read from and written back to `fields.json` in it
+#[derive(Default)]
+pub struct FieldCatalogue {
+    dir: Option<PathBuf>,
+    fields: HashMap<String, FieldInfo>,
+    loaded: bool,
+}
+
+impl FieldCatalogue {
+    const FILE_NAME: &'static str = "fields.json";
+
+    fn stored() -> MutexGuard<'static, FieldCatalogue> {
+        static CATALOGUE: OnceLock<Mutex<FieldCatalogue>> = OnceLock::new();
+        CATALOGUE
+            .get_or_init(|| Mutex::new(FieldCatalogue::default()))
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Sets the directory of the on-disk cache, `None` keeps the catalogue in memory only
+    pub fn set_cache_dir(dir: Option<PathBuf>) {
+        let mut catalogue = FieldCatalogue::stored();
+        catalogue.dir = dir;
+        catalogue.loaded = false;
+    }
+
+    /// Forgets every field, in memory and on disk
+    pub fn clear() -> Result<(), EkError> {
+        let mut catalogue = FieldCatalogue::stored();
+        catalogue.fields.clear();
+        catalogue.loaded = true;
+        match catalogue.file() {
+            Some(path) if path.exists() => fs::remove_file(&path).map_err(|e| {
+                let msg = format!("Could not remove {}: {e}", path.display());
+                EkError::new(ErrorKind::Error, msg)
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn file(&self) -> Option<PathBuf> {
+        self.dir.as_ref().map(|d| d.join(FieldCatalogue::FILE_NAME))
+    }
+
+    /// Merges the on-disk cache into memory the first time it is needed
+    fn load(&mut self) {
+        if self.loaded {
+            return;
+        }
+        self.loaded = true;
+        let Some(text) = self.file().and_then(|p| fs::read_to_string(p).ok()) else {
+            return;
+        };
+        if let Ok(fields) = serde_json::from_str::<Vec<FieldInfo>>(&text) {
+            for f in fields {
+                self.fields.entry(f.name.to_uppercase()).or_insert(f);
+            }
+        }
+    }
+
+    /// Writes the catalogue to disk. The cache only saves requests, so failing to write it is
+    /// not an error
+    fn save(&self) {
+        let Some(path) = self.file() else {
+            return;
+        };
+        let mut fields: Vec<&FieldInfo> = self.fields.values().collect();
+        fields.sort_by(|a, b| a.name.cmp(&b.name));
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        if let Ok(text) = serde_json::to_string_pretty(&fields) {
+            let _ = fs::write(path, text);
+        }
+    }
+
+    /// Definitions of `fields` in order, `None` for fields Eikon does not recognise. Fields not
+    /// in the catalogue are requested together for `instrument` in a single small payload
+    pub fn lookup(
+        connection: &Connection,
+        fields: &[String],
+        instrument: &str,
+    ) -> Result<Vec<Option<FieldInfo>>, EkError> {
+        let mut seen = HashSet::new();
+        let missing: Vec<String> = {
+            let mut catalogue = FieldCatalogue::stored();
+            catalogue.load();
+            fields
+                .iter()
+                .filter(|f| !catalogue.fields.contains_key(&f.to_uppercase()))
+                .filter(|f| seen.insert(f.to_uppercase()))
+                .cloned()
+                .collect()
+        };
+
+        let mut unknown = HashSet::new();
+        if !missing.is_empty() {
+            let probed = probe(connection, &missing, instrument)?;
+            let mut catalogue = FieldCatalogue::stored();
+            for name in &missing {
+                match probed.get(&name.to_uppercase()) {
+                    Some(info) => {
+                        catalogue.fields.insert(name.to_uppercase(), info.clone());
+                    }
+                    None => {
+                        unknown.insert(name.to_uppercase());
+                    }
+                }
+            }
+            catalogue.save();
+        }
+
+        let catalogue = FieldCatalogue::stored();
+        Ok(fields
+            .iter()
+            .map(|f| match unknown.contains(&f.to_uppercase()) {
+                true => None,
+                false => catalogue.fields.get(&f.to_uppercase()).cloned(),
+            })
+            .collect())
+    }
+
+    /// Checks that Eikon recognises every field of a datagrid request before anything is sent.
+    /// Parameters are checked by `unknown_parameters`, which only warns as the catalogue does
+    /// not know every parameter Eikon accepts
+    pub fn validate(
+        connection: &Connection,
+        fields: &Value,
+        instrument: &str,
+    ) -> Result<(), EkError> {
+        let names = field_names(fields);
+        let infos = FieldCatalogue::lookup(connection, &names, instrument)?;
+
+        let unknown: Vec<String> = names
+            .iter()
+            .zip(&infos)
+            .filter(|(_, info)| info.is_none())
+            .map(|(name, _)| name.clone())
+            .collect();
+        if !unknown.is_empty() {
+            let msg = format!(
+                "Eikon does not recognise {}, use settings validate = FALSE to send anyway",
+                unknown.join(", ")
+            );
+            let mut e = EkError::new(ErrorKind::InvalidField, msg);
+            e.fields = unknown;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Parameters of a datagrid request that are neither common nor known for the fields they
+    /// are given for, sorted. Request parameters may be known for any of the fields
+    pub fn unknown_parameters(
+        fields: &Value,
+        parameters: &Option<HashMap<String, String>>,
+    ) -> Vec<String> {
+        let mut catalogue = FieldCatalogue::stored();
+        catalogue.load();
+        catalogue.check_parameters(fields, parameters)
+    }
+
+    fn check_parameters(
+        &self,
+        fields: &Value,
+        parameters: &Option<HashMap<String, String>>,
+    ) -> Vec<String> {
+        let known_for = |names: &[String]| {
+            let mut known: HashSet<String> =
+                COMMON_PARAMETERS.iter().map(|p| p.to_lowercase()).collect();
+            for info in names
+                .iter()
+                .filter_map(|n| self.fields.get(&n.to_uppercase()))
+            {
+                known.extend(info.parameters.iter().map(|p| p.to_lowercase()));
+            }
+            known
+        };
+
+        let known = known_for(&field_names(fields));
+        let mut unknown: Vec<String> = parameters
+            .iter()
+            .flat_map(|p| p.keys())
+            .filter(|p| !known.contains(&p.to_lowercase()))
+            .cloned()
+            .collect();
+        for f in fields.as_array().into_iter().flatten() {
+            let (Some(name), Some(params)) = (f["name"].as_str(), f["parameters"].as_object())
+            else {
+                continue;
+            };
+            let known = known_for(&[name.to_string()]);
+            unknown.extend(
+                params
+                    .keys()
+                    .filter(|p| !known.contains(&p.to_lowercase()))
+                    .cloned(),
+            );
+        }
+        unknown.sort();
+        unknown.dedup();
+        unknown
+    }
+
+    /// Remembers the parameters a request was downloaded with for each of its fields, so they
+    /// are no longer reported by `unknown_parameters` and are listed by `describe`
+    pub fn record_parameters(fields: &Value, parameters: &Option<HashMap<String, String>>) {
+        let mut catalogue = FieldCatalogue::stored();
+        catalogue.load();
+        if catalogue.add_parameters(fields, parameters) {
+            catalogue.save();
+        }
+    }
+
+    /// Adds the parameters to the fields in the catalogue, true when any was new
+    fn add_parameters(
+        &mut self,
+        fields: &Value,
+        parameters: &Option<HashMap<String, String>>,
+    ) -> bool {
+        let params = request_parameters(fields, parameters);
+        let mut changed = false;
+        for name in field_names(fields) {
+            if let Some(info) = self.fields.get_mut(&name.to_uppercase()) {
+                for p in &params {
+                    if !info.parameters.iter().any(|x| x.eq_ignore_ascii_case(p)) {
+                        info.parameters.push(p.clone());
+                        changed = true;
+                    }
+                }
+            }
+        }
+        changed
+    }
+
+    /// Table of the definitions of `fields`, one row per field with a `valid` column
+    pub fn describe(connection: &Connection, fields: Vec<String>, instrument: &str) -> EkResults {
+        let infos = match FieldCatalogue::lookup(connection, &fields, instrument) {
+            Ok(i) => i,
+            Err(e) => return EkResults::Err(e),
+        };
+
+        let mut columns: Vec<Vec<Value>> = vec![Vec::new(); 5];
+        for (name, info) in fields.iter().zip(infos) {
+            columns[0].push(json!(name));
+            columns[1].push(json!(info.as_ref().map(|i| &i.display_name)));
+            columns[2].push(json!(info
+                .as_ref()
+                .and_then(|i| i.data_type)
+                .map(|t| t.name())));
+            columns[3].push(json!(info.as_ref().map(|i| i.parameters.join(","))));
+            columns[4].push(json!(info.is_some()));
+        }
+
+        let types = [
+            ColumnType::Str,
+            ColumnType::Str,
+            ColumnType::Str,
+            ColumnType::Str,
+            ColumnType::Bool,
+        ];
+        EkResults::Columns {
+            names: ["name", "display_name", "data_type", "parameters", "valid"]
+                .map(String::from)
+                .to_vec(),
+            columns: columns
+                .into_iter()
+                .zip(types)
+                .map(|(c, t)| Column::from_values(c, Some(t)))
+                .collect(),
+            failed: Vec::new(),
+        }
+    }
+}
+
+/// Requests `fields` for `instrument` and reads their definitions from the response, fields
+/// Eikon cannot resolve are left out
+fn probe(
+    connection: &Connection,
+    fields: &[String],
+    instrument: &str,
+) -> Result<HashMap<String, FieldInfo>, EkError> {
+    let names: Vec<Value> = fields.iter().map(|f| json!({ "name": f })).collect();
+    let payload = json!({
+        "requests": [{
+            "instruments": [instrument],
+            "fields": names,
+        }]
+    });
+    // The probe is not part of the caller's download, so it skips their cache, checkpoint and
+    // progress callback
+    let res = connection
+        .plain()
+        .send_request_async_handler(vec![payload], Direction::Datagrid)?;
+    match res.ok.first() {
+        Some(response) => Ok(probe_fields(response, fields)),
+        None => Err(res.no_data()),
+    }
+}
+
+fn probe_fields(response: &Value, fields: &[String]) -> HashMap<String, FieldInfo> {
+    let response = &response["responses"][0];
+    let headers = response["headers"][0]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+
+    let unresolved: HashSet<u64> = response["error"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|e| {
+            let msg = e["message"].as_str().unwrap_or("").to_lowercase();
+            UNKNOWN_FIELD_MESSAGES.iter().any(|m| msg.contains(m))
+        })
+        .filter_map(|e| e["col"].as_u64())
+        .collect();
+
+    let mut found = HashMap::new();
+    for (i, name) in fields.iter().enumerate() {
+        // Column 0 is the instrument, fields follow in the order requested
+        let col = i + 1;
+        let Some(header) = headers.get(col) else {
+            continue;
+        };
+        if unresolved.contains(&(col as u64)) {
+            continue;
+        }
+        let value = response["data"][0][col].clone();
+        let data_type = match value {
+            Value::Null => None,
+            Value::String(ref s) if s.is_empty() => None,
+            v => Some(Column::from_values(vec![v], None).column_type()),
+        };
+        let info = FieldInfo {
+            name: name.clone(),
+            display_name: header["displayName"].as_str().unwrap_or(name).to_string(),
+            data_type,
+            parameters: Vec::new(),
+        };
+        found.insert(name.to_uppercase(), info);
+    }
+    found
+}
+
+fn field_names(fields: &Value) -> Vec<String> {
+    fields
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|f| f["name"].as_str())
+        .map(String::from)
+        .collect()
+}
+
+/// Names of the request parameters followed by those given for single fields
+fn request_parameters(fields: &Value, parameters: &Option<HashMap<String, String>>) -> Vec<String> {
+    let mut names: Vec<String> = parameters.iter().flat_map(|p| p.keys().cloned()).collect();
+    for f in fields.as_array().into_iter().flatten() {
+        if let Some(p) = f["parameters"].as_object() {
+            names.extend(p.keys().cloned());
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_fields() {
+        let response = json!({
+            "responses": [{
+                "headers": [[
+                    {"displayName": "Instrument"},
+                    {"displayName": "Revenue", "field": "TR.REVENUE"},
+                    {"displayName": "TR.NOTAFIELD"},
+                    {"displayName": "IPO Date", "field": "TR.IPODATE"}
+                ]],
+                "data": [["IBM.N", 61860000000.0, null, ""]],
+                "error": [{
+                    "code": 207,
+                    "col": 2,
+                    "message": "Unable to resolve all requested fields in 'TR.NOTAFIELD'.",
+                    "row": 0
+                }]
+            }]
+        });
+        let fields = ["TR.Revenue", "TR.NotAField", "TR.IPODate"].map(String::from);
+        let found = probe_fields(&response, &fields);
+
+        assert_eq!(found.len(), 2);
+        let revenue = &found["TR.REVENUE"];
+        assert_eq!(revenue.display_name, "Revenue");
+        assert_eq!(revenue.data_type, Some(ColumnType::Float));
+        assert!(!found.contains_key("TR.NOTAFIELD"));
+        assert_eq!(found["TR.IPODATE"].data_type, None);
+    }
+
+    #[test]
+    fn test_unknown_parameters() {
+        let mut catalogue = FieldCatalogue::default();
+        catalogue.fields.insert(
+            "TR.PRICECLOSE".to_string(),
+            FieldInfo {
+                name: "TR.PriceClose".to_string(),
+                display_name: "Price Close".to_string(),
+                data_type: Some(ColumnType::Float),
+                parameters: Vec::new(),
+            },
+        );
+        let fields = json!([
+            {"name": "TR.PriceClose", "parameters": {"Adjusted": "0", "Curn": "USD"}},
+            {"name": "TR.Revenue"}
+        ]);
+        let parameters = Some(HashMap::from([
+            ("SDate".to_string(), "2020-01-01".to_string()),
+            ("Frqq".to_string(), "D".to_string()),
+        ]));
+        assert_eq!(
+            catalogue.check_parameters(&fields, &parameters),
+            vec!["Adjusted".to_string(), "Frqq".to_string()]
+        );
+
+        // Once downloaded with, a parameter is known for the field
+        let adjusted = Some(HashMap::from([("adjusted".to_string(), "0".to_string())]));
+        assert!(catalogue.add_parameters(&json!([{"name": "TR.PriceClose"}]), &adjusted));
+        assert_eq!(
+            catalogue.check_parameters(&fields, &parameters),
+            vec!["Frqq".to_string()]
+        );
+        assert!(!catalogue.add_parameters(&json!([{"name": "TR.PriceClose"}]), &adjusted));
+    }
+}
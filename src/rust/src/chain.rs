@@ -0,0 +1,257 @@
+use crate::datagrid::Datagrid;
+use crate::utils::{
+    field_builder, Column, ColumnType, EkError, EkResults, ErrorKind, FailedChunk, Fields,
+};
+use chrono::prelude::*;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+
+/// Field holding the RIC of each member when a chain is requested as instrument
+const RIC_FIELD: &str = "TR.RIC";
+
+/// Joiner/leaver history of an index, requested with the index RIC (e.g. `.SPX` for `0#.SPX`)
+const CHANGE_FIELDS: [&str; 3] = [
+    "TR.IndexJLConstituentChangeDate",
+    "TR.IndexJLConstituentRIC",
+    "TR.IndexJLConstituentRIC.change",
+];
+
+pub struct Chain {
+    datagrid: Datagrid,
+}
+
+impl Chain {
+    /// Chains nested deeper than this are returned as they are instead of expanded
+    const MAX_DEPTH: usize = 5;
+
+    pub fn new(datagrid: Datagrid) -> Self {
+        Self { datagrid }
+    }
+
+    /// Expands every chain RIC into its constituents, as of `as_of` when given. Constituents
+    /// that are chains themselves (`0#...`) are expanded in turn. Returns the RIC and the
+    /// top-level chain it came from for every constituent.
+    pub fn expand(&self, chains: Vec<String>, as_of: Option<NaiveDate>) -> EkResults {
+        let mut rics: Vec<serde_json::Value> = Vec::new();
+        let mut sources: Vec<serde_json::Value> = Vec::new();
+        let mut failed: Vec<FailedChunk> = Vec::new();
+
+        for chain in chains {
+            let mut visited = HashSet::from([chain.clone()]);
+            let constituents = match self.expand_one(&chain, as_of, 0, &mut visited, &mut failed) {
+                Ok(c) => c,
+                Err(e) => return EkResults::Err(e),
+            };
+            for ric in constituents {
+                rics.push(json!(ric));
+                sources.push(json!(chain));
+            }
+        }
+
+        if rics.is_empty() {
+            return EkResults::Err(EkError::new(ErrorKind::NoData, "No constituents found"));
+        }
+        EkResults::Columns {
+            names: vec!["RIC".to_string(), "chain".to_string()],
+            columns: vec![
+                Column::from_values(rics, Some(ColumnType::Str)),
+                Column::from_values(sources, Some(ColumnType::Str)),
+            ],
+            failed,
+        }
+    }
+
+    fn expand_one(
+        &self,
+        chain: &str,
+        as_of: Option<NaiveDate>,
+        depth: usize,
+        visited: &mut HashSet<String>,
+        failed: &mut Vec<FailedChunk>,
+    ) -> Result<Vec<String>, EkError> {
+        let mut constituents = self.current(chain, failed)?;
+        if let Some(date) = as_of {
+            let index = chain.strip_prefix("0#").unwrap_or(chain);
+            let changes = self.changes(index, date, failed)?;
+            constituents = roll_back(constituents, changes, date);
+        }
+
+        let mut rics = Vec::with_capacity(constituents.len());
+        for ric in constituents {
+            if is_chain(&ric) && depth < Chain::MAX_DEPTH && visited.insert(ric.clone()) {
+                rics.extend(self.expand_one(&ric, as_of, depth + 1, visited, failed)?);
+            } else {
+                rics.push(ric);
+            }
+        }
+        Ok(rics)
+    }
+
+    /// Current constituents, a chain passed as instrument comes back with one row per member
+    fn current(&self, chain: &str, failed: &mut Vec<FailedChunk>) -> Result<Vec<String>, EkError> {
+        let fields = field_builder(Fields::NoParams(vec![RIC_FIELD.to_string()]));
+        match self
+            .datagrid
+            .get_datagrid(vec![chain.to_string()], fields, None, settings())
+        {
+            EkResults::Columns {
+                names,
+                columns,
+                failed: f,
+            } => {
+                failed.extend(f);
+                Ok(members(chain, &names, &columns))
+            }
+            EkResults::Raw { .. } => Ok(Vec::new()),
+            EkResults::Err(e) => Err(e),
+        }
+    }
+
+    /// Joiners and leavers of `index` between `since` and today as (date, RIC, joined)
+    fn changes(
+        &self,
+        index: &str,
+        since: NaiveDate,
+        failed: &mut Vec<FailedChunk>,
+    ) -> Result<Vec<(NaiveDate, String, bool)>, EkError> {
+        let fields = field_builder(Fields::NoParams(CHANGE_FIELDS.map(String::from).to_vec()));
+        let params = HashMap::from([
+            ("SDate".to_string(), since.format("%Y-%m-%d").to_string()),
+            (
+                "EDate".to_string(),
+                Utc::now().format("%Y-%m-%d").to_string(),
+            ),
+            ("IC".to_string(), "B".to_string()),
+        ]);
+        let columns = match self.datagrid.get_datagrid(
+            vec![index.to_string()],
+            fields,
+            Some(params),
+            settings(),
+        ) {
+            EkResults::Columns {
+                columns, failed: f, ..
+            } => {
+                failed.extend(f);
+                columns
+            }
+            EkResults::Raw { .. } => return Ok(Vec::new()),
+            // An index without recorded changes comes back without data
+            EkResults::Err(e) if e.kind == ErrorKind::NoData => return Ok(Vec::new()),
+            EkResults::Err(e) => return Err(e),
+        };
+
+        // Columns are Instrument followed by CHANGE_FIELDS in order
+        let dates = column_dates(columns.get(1));
+        let rics = column_strings(columns.get(2));
+        let kinds = column_strings(columns.get(3));
+        Ok(dates
+            .into_iter()
+            .zip(rics)
+            .zip(kinds)
+            .filter_map(|((d, r), k)| {
+                let joined = k?.to_lowercase().starts_with("join");
+                Some((d?, r?, joined))
+            })
+            .collect())
+    }
+}
+
+/// Distinct RICs of the `RIC_FIELD` column, without the chain itself
+fn members(chain: &str, names: &[String], columns: &[Column]) -> Vec<String> {
+    let ric = names.iter().position(|n| n.eq_ignore_ascii_case(RIC_FIELD));
+    let mut seen = HashSet::new();
+    column_strings(ric.and_then(|i| columns.get(i)))
+        .into_iter()
+        .flatten()
+        .filter(|r| r != chain && seen.insert(r.clone()))
+        .collect()
+}
+
+fn is_chain(ric: &str) -> bool {
+    ric.starts_with("0#")
+}
+
+/// The fields requested here are known, so they skip the field catalogue. Columns are named
+/// after their fields
+fn settings() -> HashMap<String, bool> {
+    HashMap::from([
+        ("validate".to_string(), false),
+        ("field_name".to_string(), true),
+    ])
+}
+
+/// Undoes every change after `as_of`, newest first: joiners are removed and leavers put back
+fn roll_back(
+    mut constituents: Vec<String>,
+    mut changes: Vec<(NaiveDate, String, bool)>,
+    as_of: NaiveDate,
+) -> Vec<String> {
+    changes.sort_by_key(|c| std::cmp::Reverse(c.0));
+    for (date, ric, joined) in changes {
+        if date <= as_of {
+            break;
+        }
+        if joined {
+            constituents.retain(|c| *c != ric);
+        } else if !constituents.contains(&ric) {
+            constituents.push(ric);
+        }
+    }
+    constituents
+}
+
+fn column_strings(column: Option<&Column>) -> Vec<Option<String>> {
+    match column {
+        Some(Column::Str(v)) => v.clone(),
+        _ => Vec::new(),
+    }
+}
+
+fn column_dates(column: Option<&Column>) -> Vec<Option<NaiveDate>> {
+    match column {
+        Some(Column::Date(v)) => v.clone(),
+        Some(Column::DateTime(v)) => v.iter().map(|d| d.map(|d| d.date())).collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roll_back() {
+        let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+        let current = vec!["A".to_string(), "B".to_string(), "C".to_string()];
+        let changes = vec![
+            (date("2021-03-01"), "C".to_string(), true),
+            (date("2021-03-01"), "D".to_string(), false),
+            (date("2020-06-01"), "B".to_string(), true),
+            (date("2019-01-01"), "E".to_string(), false),
+        ];
+        assert_eq!(
+            roll_back(current.clone(), changes.clone(), date("2021-01-01")),
+            vec!["A", "B", "D"]
+        );
+        assert_eq!(
+            roll_back(current, changes, date("2020-01-01")),
+            vec!["A", "D"]
+        );
+    }
+
+    #[test]
+    fn test_members() {
+        let names = ["Instrument", "TR.RIC"].map(String::from).to_vec();
+        let strings = |v: &[&str]| Column::Str(v.iter().map(|s| Some(s.to_string())).collect());
+        let columns = vec![
+            strings(&["0#.DAX", "0#.DAX", "0#.DAX", "0#.DAX"]),
+            strings(&["0#.DAX", "SAPG.DE", "SIEGn.DE", "SAPG.DE"]),
+        ];
+        assert_eq!(
+            members("0#.DAX", &names, &columns),
+            vec!["SAPG.DE", "SIEGn.DE"]
+        );
+        assert!(members("0#.DAX", &names[..1], &columns).is_empty());
+    }
+}
@@ -1,16 +1,84 @@
+use chrono::{NaiveDate, Utc};
 use serde_json::{json, Value};
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::thread;
-use std::time;
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
+use crate::cache::{Checkpoint, ResponseCache};
+use crate::utils::{ChunkResults, EkError, ErrorKind, FailedChunk};
 use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use tokio::task::JoinHandle;
-use crate::utils::EkError;
 
 #[derive(Copy, Clone)]
 pub enum Direction {
     Datagrid,
     TimeSeries,
+    NewsHeadlines,
+    NewsStory,
+    SymbologySearch,
+}
+
+impl Direction {
+    /// Number of rows in a response, as reported in progress updates
+    fn rows(&self, response: &Value) -> usize {
+        let len = |v: &Value| v.as_array().map_or(0, |a| a.len());
+        match self {
+            Direction::Datagrid => len(&response["responses"][0]["data"]),
+            Direction::TimeSeries => response["timeseriesData"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|r| len(&r["dataPoints"]))
+                .sum(),
+            Direction::NewsHeadlines => len(&response["headlines"]),
+            Direction::NewsStory => 1,
+            Direction::SymbologySearch => len(&response["mappedSymbols"]),
+        }
+    }
+}
+
+/// State of a DataGrid_StandardAsync ticket after a poll
+enum Ticket {
+    Pending {
+        ticket: Value,
+        estimate: Option<Duration>,
+    },
+    Resolved(Value),
+    Expired(String),
+}
+
+/// Backoff settings used while waiting for a ticket to resolve
+#[derive(Copy, Clone, Debug)]
+pub struct TicketPolling {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    pub max_wait: Duration,
+}
+
+impl Default for TicketPolling {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+            multiplier: 2.0,
+            max_wait: Duration::from_secs(300),
+        }
+    }
+}
+
+impl TicketPolling {
+    /// Delay before poll number `attempt` (0-indexed), capped at `max_delay`
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.max(1.0).powi(attempt.min(32) as i32);
+        self.initial_delay.mul_f64(factor).min(self.max_delay)
+    }
 }
 
 impl fmt::Display for Direction {
@@ -18,239 +86,1453 @@ impl fmt::Display for Direction {
         match self {
             Direction::Datagrid => write!(f, "DataGrid_StandardAsync"),
             Direction::TimeSeries => write!(f, "TimeSeries"),
+            Direction::NewsHeadlines => write!(f, "News_Headlines"),
+            Direction::NewsStory => write!(f, "News_Story"),
+            Direction::SymbologySearch => write!(f, "SymbologySearch"),
         }
     }
 }
 
-pub struct Connection {
+/// When and how often a failed payload is sent again, shared by every direction
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    /// Fraction of each delay that is randomised, 0 gives fixed delays
+    pub jitter: f64,
+    pub retry_statuses: Vec<u16>,
+    pub retry_error_codes: Vec<i64>,
+    /// Time budget for all attempts of a call, counted from when its first payload is sent
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.5,
+            retry_statuses: vec![408, 429, 500, 502, 503, 504],
+            retry_error_codes: vec![400, 500, 2504],
+            deadline: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn stored() -> &'static Mutex<RetryPolicy> {
+        static POLICY: OnceLock<Mutex<RetryPolicy>> = OnceLock::new();
+        POLICY.get_or_init(|| Mutex::new(RetryPolicy::default()))
+    }
+
+    /// Policy used by connections that are not given one explicitly
+    pub fn global() -> RetryPolicy {
+        RetryPolicy::stored()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn set_global(policy: RetryPolicy) {
+        *RetryPolicy::stored()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = policy;
+    }
+
+    fn retries_status(&self, status: u16) -> bool {
+        self.retry_statuses.contains(&status)
+    }
+
+    fn retries_error_code(&self, code: i64) -> bool {
+        self.retry_error_codes.contains(&code)
+    }
+
+    /// Failed attempt for an error from the proxy, retried when its Eikon error code or HTTP
+    /// status is one the policy retries
+    fn attempt(&self, mut e: EkError) -> Attempt {
+        e.retryable = match (e.error_code, e.status) {
+            (Some(code), _) => self.retries_error_code(code),
+            (None, Some(status)) => self.retries_status(status),
+            (None, None) => e.retryable,
+        };
+        Attempt::from(e)
+    }
+
+    /// Backoff before attempt `attempt + 1`, with up to `jitter` of it removed at random
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = self
+            .multiplier
+            .max(1.0)
+            .powi(attempt.saturating_sub(1).min(32) as i32);
+        let delay = self.initial_delay.mul_f64(factor).min(self.max_delay);
+        let jitter = self.jitter.clamp(0.0, 1.0) * random_unit();
+        delay.mul_f64(1.0 - jitter)
+    }
+}
+
+/// Port number from the contents of a `.portInUse` file
+fn parse_port(content: &str) -> Option<u16> {
+    content.trim().parse::<u16>().ok().filter(|p| *p > 0)
+}
+
+/// Cheap random number in [0, 1) for jitter, seeded by the std hasher's random keys
+fn random_unit() -> f64 {
+    let hash = RandomState::new().build_hasher().finish();
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// A chunk that was not downloaded because the user interrupted the request
+fn cancelled_chunk(payload: Value) -> FailedChunk {
+    let e = EkError::new(ErrorKind::Cancelled, "interrupted by the user");
+    FailedChunk::new(payload, 0, e)
+}
+
+/// Usage limits of the Eikon Data API, shared by every request made from this process
+#[derive(Copy, Clone, Debug)]
+pub struct RateLimits {
+    pub per_second: u32,
+    pub per_minute: u32,
+    pub per_day: u32,
+    pub bytes_per_minute: u64,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            per_second: 5,
+            per_minute: 300,
+            per_day: 10_000,
+            bytes_per_minute: 50 * 1024 * 1024,
+        }
+    }
+}
+
+impl RateLimits {
+    /// Time the limits alone stretch `requests` requests receiving `bytes` in total over,
+    /// starting from full buckets. Requests beyond `per_day` fail instead of waiting
+    pub fn estimate(&self, requests: usize, bytes: u64) -> Duration {
+        let wait = |amount: f64, capacity: u64, window: f64| {
+            let capacity = capacity.max(1) as f64;
+            (amount - capacity).max(0.0) * window / capacity
+        };
+        let n = requests as f64;
+        let secs = wait(n, u64::from(self.per_second), 1.0)
+            .max(wait(n, u64::from(self.per_minute), 60.0))
+            .max(wait(bytes as f64, self.bytes_per_minute, 60.0));
+        Duration::from_secs_f64(secs)
+    }
+}
+
+/// Token bucket that starts full and refills continuously up to its capacity
+struct Bucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(capacity: f64, window: Duration, now: Instant) -> Self {
+        Self {
+            capacity,
+            tokens: capacity,
+            refill_per_sec: capacity / window.as_secs_f64(),
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last = now;
+    }
+
+    /// Time until the bucket holds at least `amount` tokens
+    fn wait_for(&self, amount: f64) -> Duration {
+        let missing = amount.min(self.capacity) - self.tokens;
+        if missing <= 0.0 || self.refill_per_sec <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / self.refill_per_sec)
+        }
+    }
+}
+
+/// Requests sent on one calendar day in UTC, the daily quota starts again at midnight UTC
+/// instead of refilling like the other limits
+struct DayCount {
+    date: NaiveDate,
+    used: u32,
+}
+
+/// Process wide governor enforcing `RateLimits` over all requests sent to the proxy
+pub struct RateGovernor {
+    limits: RateLimits,
+    second: Bucket,
+    minute: Bucket,
+    day: DayCount,
+    bytes: Bucket,
+}
+
+impl RateGovernor {
+    fn new(limits: RateLimits, now: Instant) -> Self {
+        Self {
+            limits,
+            second: Bucket::new(limits.per_second.max(1) as f64, Duration::from_secs(1), now),
+            minute: Bucket::new(
+                limits.per_minute.max(1) as f64,
+                Duration::from_secs(60),
+                now,
+            ),
+            day: DayCount {
+                date: NaiveDate::MIN,
+                used: 0,
+            },
+            bytes: Bucket::new(
+                limits.bytes_per_minute.max(1) as f64,
+                Duration::from_secs(60),
+                now,
+            ),
+        }
+    }
+
+    fn global() -> &'static Mutex<RateGovernor> {
+        static GOVERNOR: OnceLock<Mutex<RateGovernor>> = OnceLock::new();
+        GOVERNOR
+            .get_or_init(|| Mutex::new(RateGovernor::new(RateLimits::default(), Instant::now())))
+    }
+
+    /// Replaces the limits used by every following request
+    pub fn set_limits(limits: RateLimits) {
+        let mut governor = RateGovernor::global()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        *governor = RateGovernor::new(limits, Instant::now());
+    }
+
+    pub fn limits() -> RateLimits {
+        RateGovernor::global()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .limits
+    }
+
+    /// Takes a request token if one is available, otherwise returns how long to wait. `today`
+    /// is the current UTC date
+    fn reserve(&mut self, now: Instant, today: NaiveDate) -> Result<Duration, EkError> {
+        for bucket in [&mut self.second, &mut self.minute, &mut self.bytes] {
+            bucket.refill(now);
+        }
+        if self.day.date != today {
+            self.day = DayCount {
+                date: today,
+                used: 0,
+            };
+        }
+
+        if self.day.used >= self.limits.per_day.max(1) {
+            return Err(EkError::new(
+                ErrorKind::RateLimit,
+                format!(
+                    "the daily limit of {} requests has been reached",
+                    self.limits.per_day
+                ),
+            ));
+        }
+
+        let wait = self
+            .second
+            .wait_for(1.0)
+            .max(self.minute.wait_for(1.0))
+            .max(self.bytes.wait_for(0.0));
+        if wait == Duration::ZERO {
+            self.second.tokens -= 1.0;
+            self.minute.tokens -= 1.0;
+            self.day.used += 1;
+        }
+        Ok(wait)
+    }
+
+    /// Waits until a request may be sent without breaking any of the limits
+    async fn acquire() -> Result<(), EkError> {
+        loop {
+            let wait = RateGovernor::global()
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .reserve(Instant::now(), Utc::now().date_naive())?;
+            if wait == Duration::ZERO {
+                return Ok(());
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Accounts for the size of a response, the bucket may go negative on large responses
+    fn record_bytes(n: usize) {
+        let mut governor = RateGovernor::global()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        governor.bytes.refill(Instant::now());
+        governor.bytes.tokens -= n as f64;
+    }
+}
+
+/// Outcome of a single exchange with the proxy that did not fail outright
+enum Reply {
+    Json(Value),
+    Status(u16),
+    Unreachable(String),
+}
+
+/// Failed attempt at a payload, `Retry` when the retry policy allows sending it again
+enum Attempt {
+    Retry(EkError),
+    Fail(EkError),
+}
+
+impl From<EkError> for Attempt {
+    fn from(e: EkError) -> Self {
+        if e.retryable {
+            Attempt::Retry(e)
+        } else {
+            Attempt::Fail(e)
+        }
+    }
+}
+
+/// Bearer token from the handshake together with the moment it stops being valid
+struct Token {
+    bearer: String,
+    expires: Instant,
+}
+
+/// Whether the proxy is reached over plain HTTP or TLS
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scheme {
+    Http,
+    Https,
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scheme::Http => write!(f, "http"),
+            Scheme::Https => write!(f, "https"),
+        }
+    }
+}
+
+/// Where the proxy is and how to talk to it, by default the desktop proxy on
+/// `http://127.0.0.1:9000`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ConnectionConfig {
     app_key: String,
-    url: String,
-    port: i16,
+    host: String,
+    port: u16,
+    scheme: Scheme,
+    base_path: String,
+    headers: Vec<(String, String)>,
 }
 
-impl Connection {
-    pub fn new(app_key: String, ip: String, port: i16) -> Self {
+impl ConnectionConfig {
+    pub fn new(app_key: String) -> Self {
         Self {
             app_key,
-            url: ip,
-            port,
+            host: "127.0.0.1".to_string(),
+            port: 9000,
+            scheme: Scheme::Http,
+            base_path: String::new(),
+            headers: Vec::new(),
         }
     }
 
-    fn get_url(&self) -> String {
-        format!("http://{}", self.url)
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.host = host.into();
+        self
     }
 
-    fn get_address(&self) -> String {
-        format!("http://{}:{}", self.url, self.port)
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
     }
 
-    fn get_app_key(&self) -> &str {
-        &self.app_key
+    pub fn scheme(mut self, scheme: Scheme) -> Self {
+        self.scheme = scheme;
+        self
     }
 
-    pub fn send_request_async_handler(
-        &self,
-        payloads: Vec<Value>,
-        direction: Direction,
-    ) -> Result<Vec<Value>, EkError> {
+    /// Path the proxy endpoints are mounted under, e.g. `/eikon` when behind a reverse proxy
+    pub fn base_path(mut self, base_path: impl Into<String>) -> Self {
+        self.base_path = base_path.into();
+        self
+    }
+
+    /// Header sent with every request, on top of the ones the proxy requires
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Base URL the `/api/...` endpoints are appended to
+    fn address(&self) -> String {
+        let host = if self.host.contains(':') && !self.host.starts_with('[') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        let path = self.base_path.trim_matches('/');
+        if path.is_empty() {
+            format!("{}://{}:{}", self.scheme, host, self.port)
+        } else {
+            format!("{}://{}:{}/{}", self.scheme, host, self.port, path)
+        }
+    }
+
+    fn header_map(&self) -> Result<reqwest::header::HeaderMap, EkError> {
+        let mut map = reqwest::header::HeaderMap::new();
+        for (name, value) in &self.headers {
+            let invalid = |e: &dyn fmt::Display| {
+                EkError::new(ErrorKind::Error, format!("Invalid header {name}: {e}"))
+            };
+            let name = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| invalid(&e))?;
+            let value = reqwest::header::HeaderValue::from_str(value).map_err(|e| invalid(&e))?;
+            map.append(name, value);
+        }
+        Ok(map)
+    }
+}
+
+/// Client state kept alive between calls, one per connection configuration
+struct Session {
+    app_key: String,
+    address: String,
+    client: reqwest::Client,
+    token: tokio::sync::Mutex<Option<Token>>,
+}
+
+impl Session {
+    /// Token lifetime used when the handshake does not report `expires_in`
+    const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(3600);
+    /// Tokens this close to expiring are replaced before use
+    const TOKEN_MARGIN: Duration = Duration::from_secs(30);
+
+    fn shared(config: ConnectionConfig) -> Result<Arc<Session>, EkError> {
+        static SESSIONS: OnceLock<Mutex<HashMap<ConnectionConfig, Arc<Session>>>> = OnceLock::new();
+        let mut sessions = SESSIONS
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(session) = sessions.get(&config) {
+            return Ok(session.clone());
+        }
+        let client = match reqwest::Client::builder()
+            .default_headers(config.header_map()?)
+            .build()
+        {
+            Ok(c) => c,
+            Err(e) => return Err(EkError::new(ErrorKind::Connection, e.to_string())),
+        };
+        let session = Arc::new(Session {
+            app_key: config.app_key.clone(),
+            address: config.address(),
+            client,
+            token: tokio::sync::Mutex::new(None),
+        });
+        sessions.insert(config, session.clone());
+        Ok(session)
+    }
+
+    /// Returns a valid bearer token, doing a new handshake if the cached one is missing or
+    /// about to expire
+    async fn bearer(&self) -> Result<String, EkError> {
+        let mut token = self.token.lock().await;
+        if let Some(t) = token.as_ref() {
+            if t.expires > Instant::now() + Session::TOKEN_MARGIN {
+                return Ok(t.bearer.clone());
+            }
+        }
+        let new_token = self.handshake().await?;
+        let bearer = new_token.bearer.clone();
+        *token = Some(new_token);
+        Ok(bearer)
+    }
+
+    /// Drops the cached token, unless another task already replaced it
+    async fn invalidate(&self, bearer: &str) {
+        let mut token = self.token.lock().await;
+        if token.as_ref().is_some_and(|t| t.bearer == bearer) {
+            *token = None;
+        }
+    }
+
+    async fn handshake(&self) -> Result<Token, EkError> {
+        let address = format!("{}/api/handshake", self.address);
+        let json_body = json!({"AppKey": self.app_key, "AppScope": "trapi", "ApiVersion": "1"});
+        let res = match self
+            .client
+            .post(&address)
+            .header("CONTENT-TYPE", "application/json")
+            .header("x-tr-applicationid", &self.app_key)
+            .body(json_body.to_string())
+            .send()
+            .await
+        {
+            Err(e) => return Err(EkError::new(ErrorKind::Connection, e.to_string())),
+            Ok(r) => r,
+        };
+        match res.json::<Value>().await {
+            Err(e) => Err(EkError::new(ErrorKind::NoData, e.to_string())),
+            Ok(hk) => Session::token(hk, Instant::now()),
+        }
+    }
+
+    fn token(hk: Value, now: Instant) -> Result<Token, EkError> {
+        let Some(access_token) = hk.get("access_token").and_then(|t| t.as_str()) else {
+            return Err(EkError::new(
+                ErrorKind::Auth,
+                "Cannot get bearer access token",
+            ));
+        };
+        let lifetime = match hk.get("expires_in") {
+            Some(Value::Number(n)) => n.as_u64().map(Duration::from_secs),
+            Some(Value::String(s)) => s.parse::<u64>().ok().map(Duration::from_secs),
+            _ => None,
+        };
+        Ok(Token {
+            bearer: format!("Bearer {}", access_token),
+            expires: now + lifetime.unwrap_or(Session::DEFAULT_TOKEN_LIFETIME),
+        })
+    }
+
+    fn req_client(&self, json_body: &Value, access_token: &str) -> reqwest::RequestBuilder {
+        self.client
+            .post(format!("{}/api/v1/data", self.address))
+            .header("CONTENT_TYPE", "application/json")
+            .header("x-tr-applicationid", &self.app_key)
+            .header("Authorization", access_token)
+            .json(json_body)
+    }
+
+    /// Sends a request to the data endpoint, re-doing the handshake once if the token is rejected
+    async fn post(&self, json_body: &Value) -> Result<Reply, EkError> {
+        let bearer = self.bearer().await?;
+        match Connection::request_executioner(self.req_client(json_body, &bearer)).await? {
+            Reply::Status(401) => {
+                self.invalidate(&bearer).await;
+                let bearer = self.bearer().await?;
+                match Connection::request_executioner(self.req_client(json_body, &bearer)).await? {
+                    Reply::Status(401) => Err(EkError::new(
+                        ErrorKind::Auth,
+                        "The proxy rejected the access token",
+                    )
+                    .with_status(401)),
+                    reply => Ok(reply),
+                }
+            }
+            reply => Ok(reply),
+        }
+    }
+}
+
+/// A `.portInUse` file the proxy may have written, and the port it contained
+#[derive(Debug, Clone)]
+pub struct PortFile {
+    pub path: PathBuf,
+    pub exists: bool,
+    pub port: Option<u16>,
+    pub responding: bool,
+}
+
+/// What `Connection::discover` checked while looking for a running proxy
+#[derive(Debug, Clone, Default)]
+pub struct DiscoveryReport {
+    pub port: Option<u16>,
+    pub port_files: Vec<PortFile>,
+    /// Ports probed through `/api/status` when no port file pointed to a running proxy
+    pub probed: Vec<u16>,
+}
+
+/// State of a running request as passed to the progress callback
+#[derive(Clone, Debug, Default)]
+pub struct Progress {
+    pub total: usize,
+    pub completed: usize,
+    pub failed: usize,
+    /// Chunks that failed at least once and are being retried
+    pub retrying: usize,
+    pub rows: usize,
+    pub elapsed: Duration,
+    /// Remaining time at the pace of the chunks finished so far
+    pub eta: Option<Duration>,
+}
+
+pub type ProgressCallback = Box<dyn Fn(&Progress)>;
+
+/// Returns true when the user asked to stop the running request
+pub type InterruptCheck = fn() -> bool;
+
+/// Reported by a chunk task, tagged with the index of its payload
+enum Event {
+    Retrying(usize),
+    Done(usize, usize),
+    Failed(usize),
+}
+
+/// A payload answered from the response cache or sent to the proxy
+enum Pending {
+    Cached(Value),
+    Sent(JoinHandle<Result<Value, FailedChunk>>),
+}
+
+pub struct Connection {
+    runtime: &'static Runtime,
+    session: Arc<Session>,
+    ticket_polling: TicketPolling,
+    retry_policy: RetryPolicy,
+    cache: Option<ResponseCache>,
+    checkpoint: Option<Checkpoint>,
+    progress: Option<ProgressCallback>,
+    interrupt: Option<InterruptCheck>,
+    /// Set once an interrupt was seen, later requests on the connection are not sent
+    cancelled: Cell<bool>,
+}
+
+impl Connection {
+    /// Connection to the proxy described by `config`, the runtime, HTTP client and access
+    /// token are reused by every connection made with the same configuration
+    pub fn new(config: ConnectionConfig) -> Result<Self, EkError> {
+        Ok(Self {
+            runtime: Connection::runtime()?,
+            session: Session::shared(config)?,
+            ticket_polling: TicketPolling::default(),
+            retry_policy: RetryPolicy::global(),
+            cache: ResponseCache::global(),
+            checkpoint: None,
+            progress: None,
+            interrupt: None,
+            cancelled: Cell::new(false),
+        })
+    }
+
+    pub fn with_ticket_polling(mut self, polling: TicketPolling) -> Self {
+        self.ticket_polling = polling;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Saves every response to `checkpoint` as it arrives and answers payloads from it
+    pub fn with_checkpoint(mut self, checkpoint: Option<Checkpoint>) -> Self {
+        self.checkpoint = checkpoint;
+        self
+    }
+
+    /// Calls `progress` while requests run, at most once per `PROGRESS_INTERVAL` and once
+    /// more when they have finished
+    pub fn with_progress(mut self, progress: Option<ProgressCallback>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Calls `interrupt` while requests run, when it returns true the outstanding chunks are
+    /// aborted and come back as failed chunks of kind `Cancelled`
+    pub fn with_interrupt(mut self, interrupt: InterruptCheck) -> Self {
+        self.interrupt = Some(interrupt);
+        self
+    }
+
+    /// Connection on the same session without the response cache, checkpoint or progress
+    /// callback, for lookups that should not show up in or be saved with the caller's download
+    pub fn plain(&self) -> Connection {
+        Connection {
+            runtime: self.runtime,
+            session: Arc::clone(&self.session),
+            ticket_polling: self.ticket_polling,
+            retry_policy: self.retry_policy.clone(),
+            cache: None,
+            checkpoint: None,
+            progress: None,
+            interrupt: self.interrupt,
+            cancelled: Cell::new(false),
+        }
+    }
+
+    /// App key of the session, some entities expect it in the payload as well
+    pub fn app_key(&self) -> &str {
+        &self.session.app_key
+    }
+
+    fn runtime() -> Result<&'static Runtime, EkError> {
+        static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+        if let Some(rt) = RUNTIME.get() {
+            return Ok(rt);
+        }
         let rt = match tokio::runtime::Builder::new_multi_thread()
             .worker_threads(12)
             .enable_all()
             .build()
         {
             Ok(r) => r,
-            Err(e) => return Err(EkError::ThreadError(e.to_string())),
+            Err(e) => return Err(EkError::new(ErrorKind::Thread, e.to_string())),
         };
+        Ok(RUNTIME.get_or_init(|| rt))
+    }
 
-        let app_key = self.get_app_key().to_owned();
-        let address = self.get_address();
-        let handshake = self.handshake()?;
-        let access_token = Connection::bearer(handshake)?;
+    /// Application names used by the Data API Proxy and its predecessors
+    const PROXY_APPS: [&'static str; 3] =
+        ["Data API Proxy", "Eikon API proxy", "Eikon Scripting Proxy"];
+    const PROXY_AUTHORS: [&'static str; 2] = ["Refinitiv", "Thomson Reuters"];
+    /// Ports the desktop proxy picks from when the port file cannot be used
+    const PROXY_PORTS: std::ops::RangeInclusive<u16> = 9000..=9060;
+    const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
 
-        let delay = time::Duration::from_millis(250);
-        let mut handles = Vec::with_capacity(payloads.len());
+    /// Finds the port of the proxy on this machine, first through the `.portInUse` files
+    /// written by the proxy and then by probing the default port range concurrently
+    pub fn discover() -> Result<DiscoveryReport, EkError> {
+        let rt = Connection::runtime()?;
+        let client = match reqwest::Client::builder()
+            .timeout(Connection::PROBE_TIMEOUT)
+            .build()
+        {
+            Ok(c) => c,
+            Err(e) => return Err(EkError::new(ErrorKind::Connection, e.to_string())),
+        };
+        let mut report = DiscoveryReport::default();
 
-        for payload in payloads {
-            thread::sleep(delay);
-            handles.push(rt.spawn(Connection::send_request_async(
-                payload,
-                direction,
-                address.clone(),
-                app_key.clone(),
-                access_token.clone(),
-            )));
+        for path in Connection::port_file_locations() {
+            let exists = path.is_file();
+            let port = match std::fs::read_to_string(&path) {
+                Ok(content) => parse_port(&content),
+                Err(_) => None,
+            };
+            let responding = match port {
+                Some(p) => rt.block_on(Connection::proxy_status(client.clone(), p)),
+                None => false,
+            };
+            report.port_files.push(PortFile {
+                path,
+                exists,
+                port,
+                responding,
+            });
+            if responding {
+                report.port = port;
+                return Ok(report);
+            }
         }
 
-        Connection::join_handles(handles, &rt)
+        let handles: Vec<(u16, JoinHandle<bool>)> = Connection::PROXY_PORTS
+            .map(|p| (p, rt.spawn(Connection::proxy_status(client.clone(), p))))
+            .collect();
+        for (p, handle) in handles {
+            report.probed.push(p);
+            if rt.block_on(handle).unwrap_or(false) && report.port.is_none() {
+                report.port = Some(p);
+            }
+        }
+        Ok(report)
     }
 
-    fn handshake(&self) -> Result<Value, EkError> {
-        let address = format!("{}/api/handshake", self.get_address());
-        let app_key = self.get_app_key();
-        let json_body = json!({"AppKey": app_key, "AppScope": "trapi", "ApiVersion": "1"});
-        let client = reqwest::blocking::Client::new();
+    /// Whether a proxy answers `/api/status` on `port` of this machine
+    pub fn status(port: u16) -> Result<bool, EkError> {
+        let rt = Connection::runtime()?;
+        let client = match reqwest::Client::builder()
+            .timeout(Connection::PROBE_TIMEOUT)
+            .build()
+        {
+            Ok(c) => c,
+            Err(e) => return Err(EkError::new(ErrorKind::Connection, e.to_string())),
+        };
+        Ok(rt.block_on(Connection::proxy_status(client, port)))
+    }
+
+    async fn proxy_status(client: reqwest::Client, port: u16) -> bool {
         match client
-            .post(&address)
-            .header("CONTENT-TYPE", "application/json")
-            .header("x-tr-applicationid", app_key)
-            .body(json_body.to_string())
+            .get(format!("http://127.0.0.1:{}/api/status", port))
             .send()
+            .await
         {
-            Err(e) => Err(EkError::ConnectionError(e.to_string())),
-            Ok(r) => match r.json() {
-                Err(e) => Err(EkError::NoData(e.to_string())),
-                Ok(r) => Ok(r),
-            },
+            Ok(r) => r.status() == reqwest::StatusCode::OK,
+            Err(_) => false,
         }
     }
 
-    fn join_handles(
-        handles: Vec<JoinHandle<Result<Option<Value>, EkError>>>,
-        rt: &Runtime,
-    ) -> Result<Vec<Value>, EkError> {
-        let mut res = Vec::new();
-        for handle in handles {
-            match rt.block_on(handle) {
-                Ok(Ok(Some(v))) => res.push(v),
-                Ok(Ok(None)) => {}
-                Ok(Err(e)) => return Err(e),
-                Err(e) => return Err(EkError::ThreadError(e.to_string())),
+    /// Candidate `.portInUse` files in the per-user application directories, same
+    /// locations as rappdirs' user_config_dir (Linux) and roaming user_data_dir (others)
+    fn port_file_locations() -> Vec<PathBuf> {
+        let env_dir = |var: &str| std::env::var_os(var).map(PathBuf::from);
+        let home = env_dir("HOME").or_else(|| env_dir("USERPROFILE"));
+        let mut paths = Vec::new();
+
+        for author in Connection::PROXY_AUTHORS {
+            for app in Connection::PROXY_APPS {
+                let dir = if cfg!(target_os = "windows") {
+                    env_dir("APPDATA").map(|d| d.join(author).join(app))
+                } else if cfg!(target_os = "macos") {
+                    home.as_ref()
+                        .map(|h| h.join("Library").join("Application Support").join(app))
+                } else {
+                    env_dir("XDG_CONFIG_HOME")
+                        .or_else(|| home.as_ref().map(|h| h.join(".config")))
+                        .map(|d| d.join(app))
+                };
+                if let Some(d) = dir {
+                    let path = d.join(".portInUse");
+                    if !paths.contains(&path) {
+                        paths.push(path);
+                    }
+                }
             }
         }
-        Ok(res)
+        paths
     }
 
-    fn bearer(hk: Value) -> Result<String, EkError> {
-        match hk.get("access_token") {
-            None => Err(EkError::AuthError(
-                "Cannot get bearer access token".to_string(),
-            )),
-            Some(r) => Ok(format!("Bearer {}", r)),
+    pub fn send_request_async_handler(
+        &self,
+        payloads: Vec<Value>,
+        direction: Direction,
+    ) -> Result<ChunkResults, EkError> {
+        let rt = self.runtime;
+        let cached: Vec<Option<Value>> = payloads
+            .iter()
+            .map(|p| {
+                let checkpoint = self.checkpoint.as_ref().and_then(|c| c.get(direction, p));
+                checkpoint.or_else(|| self.cache.as_ref().and_then(|c| c.get(direction, p)))
+            })
+            .collect();
+
+        // Fail fast if the proxy cannot be reached, and warm the token for the tasks
+        if !self.cancelled.get() && cached.iter().any(|c| c.is_none()) {
+            self.wait_for_token()?;
         }
+
+        if self.cancelled.get() {
+            let mut res = ChunkResults::default();
+            for (payload, hit) in payloads.into_iter().zip(cached) {
+                match hit {
+                    Some(response) => res.push_ok(payload, response),
+                    None => res.failed.push(cancelled_chunk(payload)),
+                }
+            }
+            return Ok(res);
+        }
+
+        let retry = Arc::new(self.retry_policy.clone());
+        let deadline = retry.deadline.map(|d| Instant::now() + d);
+        let (events, receiver) = match self.progress.is_some() || self.interrupt.is_some() {
+            true => {
+                let (tx, rx) = unbounded_channel();
+                (Some(tx), Some(rx))
+            }
+            false => (None, None),
+        };
+        let mut handles = Vec::with_capacity(payloads.len());
+
+        for (i, (payload, hit)) in payloads.into_iter().zip(cached).enumerate() {
+            let pending = match hit {
+                Some(response) => Pending::Cached(response),
+                None => {
+                    let events = events.clone().map(|tx| (i, tx));
+                    let task = Connection::send_request_async(
+                        payload.clone(),
+                        direction,
+                        self.session.clone(),
+                        self.ticket_polling,
+                        retry.clone(),
+                        deadline,
+                        events.clone(),
+                    );
+                    let checkpoint = self.checkpoint.clone().map(|c| (c, payload.clone()));
+                    Pending::Sent(rt.spawn(async move {
+                        let res = task.await;
+                        if let (Some((c, payload)), Ok(v)) = (&checkpoint, &res) {
+                            c.put(direction, payload, v);
+                        }
+                        if let Some((i, tx)) = events {
+                            let _ = tx.send(match &res {
+                                Ok(v) => Event::Done(i, direction.rows(v)),
+                                Err(_) => Event::Failed(i),
+                            });
+                        }
+                        res
+                    }))
+                }
+            };
+            handles.push((payload, pending));
+        }
+        // Only the tasks hold a sender now, so the channel closes once they have all ended
+        drop(events);
+
+        if let Some(rx) = receiver {
+            self.watch(&handles, direction, rx);
+        }
+        Ok(self.join_handles(handles, direction))
     }
 
-    fn req_client(
-        json_body: &Value,
-        address: &str,
-        app_key: &str,
-        access_token: Option<&str>,
-    ) -> reqwest::RequestBuilder {
-        let client = reqwest::Client::new();
-        let build = client
-            .post(format!("{}/api/v1/data", address))
-            .header("CONTENT_TYPE", "application/json")
-            .header("x-tr-applicationid", app_key)
-            .json(json_body);
+    /// Shortest time between two calls of the progress callback
+    const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+    /// Shortest time between two interrupt checks
+    const INTERRUPT_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// Waits for the session token, checking the interrupt while the proxy has not answered.
+    /// An interrupt marks the connection as cancelled instead of failing
+    fn wait_for_token(&self) -> Result<(), EkError> {
+        let mut bearer = std::pin::pin!(self.session.bearer());
+        loop {
+            let res = self.runtime.block_on(async {
+                tokio::time::timeout(Connection::INTERRUPT_INTERVAL, &mut bearer).await
+            });
+            match res {
+                Ok(token) => return token.map(|_| ()),
+                Err(_) if self.interrupt.is_some_and(|interrupted| interrupted()) => {
+                    self.cancelled.set(true);
+                    return Ok(());
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Follows the chunk tasks until all have ended, passing their progress to the progress
+    /// callback. Aborts the tasks still running when the interrupt check fires
+    fn watch(
+        &self,
+        handles: &[(Value, Pending)],
+        direction: Direction,
+        mut rx: UnboundedReceiver<Event>,
+    ) {
+        let report = |p: &Progress| {
+            if let Some(f) = &self.progress {
+                f(p)
+            }
+        };
+        let start = Instant::now();
+        let mut progress = Progress {
+            total: handles.len(),
+            ..Progress::default()
+        };
+        for (_, pending) in handles {
+            if let Pending::Cached(v) = pending {
+                progress.completed += 1;
+                progress.rows += direction.rows(v);
+            }
+        }
+        let cached = progress.completed;
+        let mut retrying = HashSet::new();
+        let mut last_report: Option<Instant> = None;
+        let mut last_check = Instant::now();
+
+        loop {
+            let finished = progress.completed + progress.failed;
+            let sent = finished - cached;
+            progress.retrying = retrying.len();
+            progress.elapsed = start.elapsed();
+            progress.eta = (sent > 0).then(|| {
+                progress
+                    .elapsed
+                    .mul_f64((progress.total - finished) as f64 / sent as f64)
+            });
+            if finished == progress.total {
+                report(&progress);
+                return;
+            }
+            if last_report.is_none_or(|t| t.elapsed() >= Connection::PROGRESS_INTERVAL) {
+                report(&progress);
+                last_report = Some(Instant::now());
+            }
+            if last_check.elapsed() >= Connection::INTERRUPT_INTERVAL {
+                if self.interrupt.is_some_and(|interrupted| interrupted()) {
+                    for (_, pending) in handles {
+                        if let Pending::Sent(h) = pending {
+                            h.abort();
+                        }
+                    }
+                    self.cancelled.set(true);
+                    return;
+                }
+                last_check = Instant::now();
+            }
 
-        match access_token {
-            None => build,
-            Some(r) => build.header("Authorization", r),
+            let event = self.runtime.block_on(async {
+                tokio::time::timeout(Connection::INTERRUPT_INTERVAL, rx.recv()).await
+            });
+            match event {
+                Ok(Some(Event::Retrying(i))) => {
+                    retrying.insert(i);
+                }
+                Ok(Some(Event::Done(i, rows))) => {
+                    retrying.remove(&i);
+                    progress.completed += 1;
+                    progress.rows += rows;
+                }
+                Ok(Some(Event::Failed(i))) => {
+                    retrying.remove(&i);
+                    progress.failed += 1;
+                }
+                // Every task has ended, one that panicked reports nothing
+                Ok(None) => return,
+                Err(_) => {}
+            }
         }
     }
 
+    /// Collects the responses in payload order, storing the ones that were sent in the cache
+    fn join_handles(&self, handles: Vec<(Value, Pending)>, direction: Direction) -> ChunkResults {
+        let mut res = ChunkResults::default();
+        for (payload, pending) in handles {
+            let handle = match pending {
+                Pending::Cached(v) => {
+                    res.push_ok(payload, v);
+                    continue;
+                }
+                Pending::Sent(h) => h,
+            };
+            match self.runtime.block_on(handle) {
+                Ok(Ok(v)) => {
+                    if let Some(cache) = &self.cache {
+                        cache.put(direction, &payload, &v);
+                    }
+                    res.push_ok(payload, v)
+                }
+                Ok(Err(f)) => res.failed.push(f),
+                Err(e) if e.is_cancelled() => res.failed.push(cancelled_chunk(payload)),
+                Err(e) => res.failed.push(FailedChunk::new(
+                    payload,
+                    0,
+                    EkError::new(ErrorKind::Thread, e.to_string()),
+                )),
+            }
+        }
+        res
+    }
+
     fn entity_assembler(payload: &Value, direction: &Direction) -> Value {
         let dir = direction.to_string();
         json!({"Entity": {"E": dir, "W": payload}})
     }
 
-    async fn request_executioner(req: reqwest::RequestBuilder) -> Result<Option<Value>, EkError> {
+    async fn request_executioner(req: reqwest::RequestBuilder) -> Result<Reply, EkError> {
+        RateGovernor::acquire().await?;
         let req_res = match req.send().await {
             Ok(r) => r,
-            Err(_) => return Ok(None),
+            Err(e) => return Ok(Reply::Unreachable(e.to_string())),
         };
 
         if !req_res.status().is_success() {
-            return Ok(None);
+            return Ok(Reply::Status(req_res.status().as_u16()));
         }
 
-        match req_res.json::<Value>().await {
-            Ok(r) => Ok(Some(r)),
-            Err(e) => Err(EkError::NoData(e.to_string())),
+        let bytes = match req_res.bytes().await {
+            Ok(b) => b,
+            Err(e) => return Err(EkError::new(ErrorKind::NoData, e.to_string())),
+        };
+        RateGovernor::record_bytes(bytes.len());
+
+        match serde_json::from_slice::<Value>(&bytes) {
+            Ok(r) => Ok(Reply::Json(r)),
+            Err(e) => Err(EkError::new(ErrorKind::NoData, e.to_string())),
         }
     }
 
     async fn send_request_async(
         payload: Value,
         direction: Direction,
-        address: String,
-        app_key: String,
-        access_token: String,
-    ) -> Result<Option<Value>, EkError> {
+        session: Arc<Session>,
+        polling: TicketPolling,
+        retry: Arc<RetryPolicy>,
+        deadline: Option<Instant>,
+        events: Option<(usize, UnboundedSender<Event>)>,
+    ) -> Result<Value, FailedChunk> {
         let body = Connection::entity_assembler(&payload, &direction);
         let mut trial = 0;
 
         loop {
             trial += 1;
-            let req = Connection::req_client(&body, &address, &app_key, Some(&access_token));
-
-            let json_res = match Connection::request_executioner(req).await {
-                Ok(r) => r,
-                Err(e) => return Err(e),
+            // The deadline also bounds the attempt itself, including waiting for its ticket
+            let attempt = Connection::send_once(&body, direction, &session, polling, &retry);
+            let res = match deadline {
+                None => attempt.await,
+                Some(d) => tokio::time::timeout_at(d.into(), attempt)
+                    .await
+                    .unwrap_or_else(|_| Err(Attempt::Fail(Connection::deadline_error(&retry)))),
+            };
+            let e = match res {
+                Ok(r) => return Ok(r),
+                Err(Attempt::Fail(e)) => return Err(FailedChunk::new(payload, trial, e)),
+                Err(Attempt::Retry(e)) => e,
             };
 
-            let Some(req_res) = json_res else {
-                if trial < 5 {
-                    continue;
-                } else {
-                    return Ok(None);
-                }
+            let wait = retry.delay(trial);
+            let out_of_time = deadline.is_some_and(|d| Instant::now() + wait > d);
+            if trial >= retry.max_attempts || out_of_time {
+                return Err(FailedChunk::new(payload, trial, e));
+            }
+            if let Some((i, tx)) = &events {
+                let _ = tx.send(Event::Retrying(*i));
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn deadline_error(retry: &RetryPolicy) -> EkError {
+        let secs = retry.deadline.unwrap_or_default().as_secs();
+        let msg = format!("No answer within the retry deadline of {secs} seconds");
+        EkError::new(ErrorKind::Connection, msg)
+    }
+
+    /// A single attempt at a payload, including waiting for its ticket if one is issued
+    async fn send_once(
+        body: &Value,
+        direction: Direction,
+        session: &Session,
+        polling: TicketPolling,
+        retry: &RetryPolicy,
+    ) -> Result<Value, Attempt> {
+        let req_res = match session.post(body).await.map_err(Attempt::Fail)? {
+            Reply::Json(r) => r,
+            Reply::Status(status) => return Err(retry.attempt(EkError::from_status(status))),
+            Reply::Unreachable(e) => {
+                return Err(Attempt::Retry(EkError::new(ErrorKind::Connection, e)));
+            }
+        };
+
+        if let Some(error_code) = req_res.get("ErrorCode") {
+            let Some(code) = error_code.as_i64() else {
+                return Err(Attempt::Fail(EkError::new(
+                    ErrorKind::Api,
+                    format!(
+                        "Could not parse ErrorCode as i64, {}: {}",
+                        error_code, req_res["ErrorMessage"]
+                    ),
+                )));
             };
+            let e = EkError::from_eikon(code, &req_res["ErrorMessage"]);
+            return Err(retry.attempt(e));
+        }
 
-            match direction {
-                Direction::Datagrid => match req_res.get("responses") {
-                    Some(r) => match r[0].get("ticket") {
-                        None => return Ok(Some(req_res)),
-                        Some(ticket) => {
-                            let _ =
-                                Connection::ticket_req(ticket, &direction, &address, &app_key)
-                                    .await;
-                        }
-                    },
-                    None => match req_res.get("ErrorCode") {
-                        None => return Ok(None),
-                        Some(error_code) => match error_code.as_u64() {
-                            None => {
-                                return Err(EkError::Error(format!(
-                                    "Could not parse ErrorCode as u64, {}: {}",
-                                    error_code, req_res["ErrorMessage"]
-                                )))
-                            }
-                            Some(e) => match e {
-                                2504u64 | 500u64 | 400u64 => {}
-                                _ => {
-                                    return Err(EkError::Error(format!(
-                                        "{}: {}",
-                                        error_code, req_res["ErrorMessage"]
-                                    )))
-                                }
-                            },
-                        },
-                    },
+        match direction {
+            Direction::Datagrid => match req_res.get("responses") {
+                None => Err(Attempt::Fail(EkError::new(
+                    ErrorKind::NoData,
+                    "Response did not contain any data",
+                ))),
+                Some(r) => match r[0].get("ticket") {
+                    None => Ok(req_res),
+                    Some(ticket) => {
+                        let estimate = Connection::estimated_duration(&r[0]);
+                        Connection::ticket_req(ticket, estimate, &direction, session, polling)
+                            .await
+                            .map_err(Attempt::Fail)
+                    }
                 },
-                Direction::TimeSeries => return Ok(Some(req_res)),
+            },
+            Direction::TimeSeries => Ok(req_res),
+            Direction::NewsHeadlines => match req_res.get("headlines") {
+                None => Err(Attempt::Fail(EkError::new(
+                    ErrorKind::NoData,
+                    "Response did not contain any headlines",
+                ))),
+                Some(_) => Ok(req_res),
+            },
+            Direction::NewsStory => {
+                if ["story", "viewURL", "webURL"]
+                    .iter()
+                    .any(|k| req_res.get(k).is_some())
+                {
+                    Ok(req_res)
+                } else {
+                    Err(Attempt::Fail(EkError::new(
+                        ErrorKind::NoData,
+                        "Response did not contain a story",
+                    )))
+                }
             }
+            Direction::SymbologySearch => match req_res.get("mappedSymbols") {
+                None => Err(Attempt::Fail(EkError::new(
+                    ErrorKind::NoData,
+                    "Response did not contain any mapped symbols",
+                ))),
+                Some(_) => Ok(req_res),
+            },
         }
     }
 
+    /// Polls a ticket with exponential backoff until the server resolves it, reports it as
+    /// expired, or `polling.max_wait` has passed.
     async fn ticket_req(
         ticket: &Value,
+        estimate: Option<Duration>,
         direction: &Direction,
-        address: &str,
-        app_key: &str,
+        session: &Session,
+        polling: TicketPolling,
     ) -> Result<Value, EkError> {
+        let started = Instant::now();
+        let mut ticket = ticket.clone();
+        let mut attempt = 0u32;
+        let mut wait = estimate
+            .map(|e| e.min(polling.max_delay))
+            .unwrap_or_else(|| polling.delay(0));
+
         loop {
+            if started.elapsed() + wait > polling.max_wait {
+                return Err(EkError::new(
+                    ErrorKind::TicketTimeout,
+                    format!(
+                        "ticket {} was not resolved within {} seconds",
+                        ticket,
+                        polling.max_wait.as_secs()
+                    ),
+                ));
+            }
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+            wait = polling.delay(attempt);
+
             let payload = json!({"requests": [{"ticket": ticket}]});
             let body = Connection::entity_assembler(&payload, direction);
-            let req = Connection::req_client(&body, address, app_key, None);
-            let json_res = match Connection::request_executioner(req).await {
-                Ok(r) => r,
-                Err(e) => return Err(e),
+            let Reply::Json(json_res) = session.post(&body).await? else {
+                continue;
             };
-            match json_res {
-                None => continue,
-                Some(r) => return Ok(r),
+            match Connection::ticket_status(json_res) {
+                Ticket::Pending {
+                    ticket: t,
+                    estimate,
+                } => {
+                    ticket = t;
+                    if let Some(e) = estimate {
+                        wait = wait.max(e.min(polling.max_delay));
+                    }
+                }
+                Ticket::Resolved(r) => return Ok(r),
+                Ticket::Expired(e) => return Err(EkError::new(ErrorKind::TicketExpired, e)),
             }
         }
     }
+
+    fn estimated_duration(response: &Value) -> Option<Duration> {
+        response
+            .get("estimatedDuration")
+            .and_then(|d| d.as_u64())
+            .map(Duration::from_millis)
+    }
+
+    fn ticket_status(res: Value) -> Ticket {
+        if let Some(r) = res.get("responses").and_then(|r| r.get(0)) {
+            return match r.get("ticket") {
+                Some(ticket) => Ticket::Pending {
+                    ticket: ticket.clone(),
+                    estimate: Connection::estimated_duration(r),
+                },
+                None => Ticket::Resolved(res),
+            };
+        }
+        match res.get("ErrorCode") {
+            None => Ticket::Expired("Ticket response did not contain any data".to_string()),
+            Some(error_code) => Ticket::Expired(format!("{}: {}", error_code, res["ErrorMessage"])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ticket_status() {
+        let pending = json!({"responses": [{"ticket": "abc", "estimatedDuration": 2000}]});
+        assert!(matches!(
+            Connection::ticket_status(pending),
+            Ticket::Pending { ticket, estimate: Some(e) } if ticket == "abc" && e == Duration::from_secs(2)
+        ));
+
+        let resolved = json!({"responses": [{"data": [["AAPL.O", 1]], "headers": []}]});
+        assert!(matches!(
+            Connection::ticket_status(resolved),
+            Ticket::Resolved(_)
+        ));
+
+        let expired = json!({"ErrorCode": 400, "ErrorMessage": "Ticket expired"});
+        assert!(matches!(
+            Connection::ticket_status(expired),
+            Ticket::Expired(_)
+        ));
+    }
+
+    #[test]
+    fn test_ticket_polling_delay() {
+        let polling = TicketPolling {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            multiplier: 2.0,
+            max_wait: Duration::from_secs(5),
+        };
+        assert_eq!(polling.delay(0), Duration::from_millis(100));
+        assert_eq!(polling.delay(3), Duration::from_millis(800));
+        assert_eq!(polling.delay(4), Duration::from_millis(1000));
+        assert_eq!(polling.delay(u32::MAX), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_rate_governor() {
+        let start = Instant::now();
+        let limits = RateLimits {
+            per_second: 2,
+            per_minute: 3,
+            per_day: 4,
+            bytes_per_minute: 60,
+        };
+        let mut governor = RateGovernor::new(limits, start);
+        let today = NaiveDate::from_ymd_opt(2024, 5, 31).unwrap();
+
+        assert_eq!(governor.reserve(start, today).unwrap(), Duration::ZERO);
+        assert_eq!(governor.reserve(start, today).unwrap(), Duration::ZERO);
+        assert_eq!(
+            governor.reserve(start, today).unwrap(),
+            Duration::from_millis(500)
+        );
+
+        // Second bucket refilled, minute bucket has one request left
+        let later = start + Duration::from_secs(1);
+        assert_eq!(governor.reserve(later, today).unwrap(), Duration::ZERO);
+        assert!(governor.reserve(later, today).unwrap() > Duration::from_secs(10));
+
+        // Oversized response blocks until the byte budget recovers
+        governor.bytes.tokens = -30.0;
+        let much_later = start + Duration::from_secs(60);
+        assert_eq!(governor.reserve(much_later, today).unwrap(), Duration::ZERO);
+        assert!(governor.reserve(much_later, today).is_err());
+
+        // The daily quota starts again on the next day, not as time passes
+        let next_day = start + Duration::from_secs(3600);
+        assert!(governor.reserve(next_day, today).is_err());
+        let tomorrow = today.succ_opt().unwrap();
+        assert_eq!(
+            governor.reserve(next_day, tomorrow).unwrap(),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn test_session_token() {
+        let now = Instant::now();
+        let token = Session::token(json!({"access_token": "abc", "expires_in": 60}), now).unwrap();
+        assert_eq!(token.bearer, "Bearer abc");
+        assert_eq!(token.expires, now + Duration::from_secs(60));
+
+        let token = Session::token(json!({"access_token": "abc"}), now).unwrap();
+        assert_eq!(token.expires, now + Session::DEFAULT_TOKEN_LIFETIME);
+
+        assert!(Session::token(json!({"error": "bad key"}), now).is_err());
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let mut policy = RetryPolicy {
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(500));
+        assert_eq!(policy.delay(3), Duration::from_secs(2));
+        assert_eq!(policy.delay(20), Duration::from_secs(30));
+        assert!(policy.retries_status(503));
+        assert!(!policy.retries_status(404));
+        assert!(policy.retries_error_code(2504));
+
+        policy.jitter = 1.0;
+        for _ in 0..20 {
+            assert!(policy.delay(1) <= Duration::from_millis(500));
+        }
+
+        // Only the policy decides what is retried
+        let gateway = EkError::from_eikon(2504, &json!("Gateway Time-out"));
+        assert!(matches!(policy.attempt(gateway), Attempt::Retry(_)));
+        assert!(matches!(
+            policy.attempt(EkError::from_status(501)),
+            Attempt::Fail(_)
+        ));
+        policy.retry_error_codes.clear();
+        let gateway = EkError::from_eikon(2504, &json!("Gateway Time-out"));
+        assert!(matches!(policy.attempt(gateway), Attempt::Fail(_)));
+    }
+
+    #[test]
+    fn test_parse_port() {
+        assert_eq!(parse_port("9000\n"), Some(9000));
+        assert_eq!(parse_port(" 40001 "), Some(40001));
+        assert_eq!(parse_port(""), None);
+        assert_eq!(parse_port("0"), None);
+        assert_eq!(parse_port("not a port"), None);
+    }
+
+    #[test]
+    fn test_connection_config_address() {
+        let config = ConnectionConfig::new("key".to_string());
+        assert_eq!(config.address(), "http://127.0.0.1:9000");
+
+        let config = config
+            .host("workstation.local")
+            .port(40443)
+            .scheme(Scheme::Https)
+            .base_path("/eikon/");
+        assert_eq!(config.address(), "https://workstation.local:40443/eikon");
+
+        let config = ConnectionConfig::new("key".to_string()).host("::1");
+        assert_eq!(config.address(), "http://[::1]:9000");
+
+        let config = ConnectionConfig::new("key".to_string()).header("bad header", "x");
+        assert!(config.header_map().is_err());
+    }
+
+    #[test]
+    fn test_interrupt_during_handshake() {
+        // Accepts the connection but never answers the handshake
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = ConnectionConfig::new("key".to_string()).port(port);
+        let connection = Connection::new(config).unwrap().with_interrupt(|| true);
+
+        let payloads = vec![json!({"a": 1}), json!({"a": 2})];
+        let res = connection
+            .send_request_async_handler(payloads, Direction::Datagrid)
+            .unwrap();
+        assert!(res.ok.is_empty());
+        assert_eq!(res.failed.len(), 2);
+        assert_eq!(res.failed[0].error.kind, ErrorKind::Cancelled);
+        drop(listener);
+    }
+
+    #[test]
+    fn test_deadline_bounds_attempt() {
+        // Accepts the connection but never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let session = Session::shared(ConnectionConfig::new("key".to_string()).port(port)).unwrap();
+        let retry = Arc::new(RetryPolicy {
+            deadline: Some(Duration::from_millis(200)),
+            ..RetryPolicy::default()
+        });
+        let deadline = retry.deadline.map(|d| Instant::now() + d);
+
+        let task = Connection::send_request_async(
+            json!({"a": 1}),
+            Direction::Datagrid,
+            session,
+            TicketPolling::default(),
+            retry,
+            deadline,
+            None,
+        );
+        let failed = Connection::runtime().unwrap().block_on(task).unwrap_err();
+        assert_eq!(failed.attempts, 1);
+        assert!(failed.error.message.contains("deadline"));
+        drop(listener);
+    }
 }
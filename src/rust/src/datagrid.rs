@@ -1,10 +1,11 @@
-use std::collections::HashMap;
-use std::cmp::min;
-use serde_json::{json, Value};
-use chrono::prelude::*;
+use crate::catalogue::FieldCatalogue;
 use crate::connection::{Connection, Direction};
-use crate::utils::{clean_string, EkResults, EkError};
-
+use crate::plan::{PlannedPayload, RequestPlan};
+use crate::utils::{clean_string, Column, EkError, EkResults, ErrorKind, Table};
+use chrono::prelude::*;
+use serde_json::{json, Value};
+use std::cmp::min;
+use std::collections::HashMap;
 
 enum Frequency {
     Daily,
@@ -28,7 +29,6 @@ impl Frequency {
     }
 }
 
-
 pub struct Datagrid {
     connection: Connection,
 }
@@ -39,7 +39,6 @@ impl Datagrid {
     }
 
     fn assemble_payload(
-        &self,
         instruments: Vec<String>,
         fields: &Value,
         param: &Option<HashMap<String, String>>,
@@ -69,68 +68,147 @@ impl Datagrid {
         settings: HashMap<String, bool>,
     ) -> EkResults {
         let direction = Direction::Datagrid;
-        let group_size = match groups(&parameters) {
+        let validate = settings.get("validate").copied().unwrap_or(true);
+        if let (true, Some(first)) = (validate, instruments.first()) {
+            if let Err(e) = FieldCatalogue::validate(&self.connection, &fields, first) {
+                return EkResults::Err(e);
+            }
+        }
+        let group_size = match field_groups(&fields, &parameters) {
             Ok(r) => r,
             Err(e) => return EkResults::Err(e),
         };
         let mut payloads: Vec<Value> = Vec::new();
         for chunk in instruments.chunks(group_size) {
             let inst_chunk = chunk.to_vec();
-            payloads.push(self.assemble_payload(inst_chunk, &fields, &parameters));
+            payloads.push(Datagrid::assemble_payload(inst_chunk, &fields, &parameters));
         }
 
-        let res = match self.connection.send_request_async_handler(payloads, direction) {
+        let res = match self
+            .connection
+            .send_request_async_handler(payloads, direction)
+        {
             Ok(r) => r,
             Err(e) => return EkResults::Err(e),
         };
 
-        if res.is_empty() {
-            return EkResults::Err(EkError::NoData("No data returned from Refinitiv".to_string()));
+        if res.ok.is_empty() {
+            return EkResults::Err(res.no_data());
+        }
+        if res.failed.is_empty() {
+            FieldCatalogue::record_parameters(&fields, &parameters);
         }
 
         if *settings.get("raw").unwrap_or(&false) {
-            EkResults::Raw(res)
+            EkResults::Raw {
+                data: res.ok,
+                failed: res.failed,
+            }
         } else {
             let field_name = settings.get("field_name").copied().unwrap_or(false);
-            match to_columns(res, field_name) {
-                Ok((names, columns)) => EkResults::Columns { names, columns },
+            match to_columns(res.ok, field_name) {
+                Ok((names, columns)) => EkResults::Columns {
+                    names,
+                    columns: columns
+                        .into_iter()
+                        .map(|c| Column::from_values(c, None))
+                        .collect(),
+                    failed: res.failed,
+                },
                 Err(e) => EkResults::Err(e),
             }
         }
     }
+
+    /// The payloads `get_datagrid` would send for this request, without sending them
+    pub fn plan(
+        instruments: &[String],
+        fields: &Value,
+        parameters: &Option<HashMap<String, String>>,
+    ) -> Result<RequestPlan, EkError> {
+        let rows = field_rows(fields, parameters)?.ceil().max(1.0) as usize;
+        let group_size = field_groups(fields, parameters)?;
+        let param = |k: &str| parameters.as_ref().and_then(|p| p.get(k)).cloned();
+        let payloads = instruments
+            .chunks(group_size)
+            .map(|chunk| PlannedPayload {
+                instruments: chunk.to_vec(),
+                start: param("SDate"),
+                end: param("EDate"),
+                rows: chunk.len() * rows,
+            })
+            .collect();
+        Ok(RequestPlan {
+            payloads,
+            columns: fields.as_array().map_or(0, |f| f.len()) + 1,
+        })
+    }
+}
+
+/// Rows a single instrument returns, one when no start date is given
+fn rows_per_instrument(parameters: &Option<HashMap<String, String>>) -> Result<f32, EkError> {
+    let Some(param) = parameters else {
+        return Ok(1f32);
+    };
+    let Some(s_date) = param.get("SDate") else {
+        return Ok(1f32);
+    };
+    let start_date = str_to_date(s_date.as_str())?;
+    let end_date = match param.get("EDate") {
+        None => Utc::now().date_naive(),
+        Some(value) => str_to_date(value.as_str())?,
+    };
+    let dur = end_date.signed_duration_since(start_date);
+    let frq = Frequency::new(param.get("Frq").unwrap_or(&String::from("d")).as_str());
+    Ok(match frq {
+        Frequency::Daily => dur.num_days() as f32,
+        Frequency::Weekly => (dur.num_days() as f32) / 7f32,
+        Frequency::Monthly => (dur.num_days() as f32) / 30f32,
+        Frequency::Quarterly => (dur.num_days() as f32) / 90f32,
+        Frequency::SemiAnnual => (dur.num_days() as f32) / 180f32,
+        Frequency::Annual => (dur.num_days() as f32) / 365f32,
+    })
 }
 
-fn groups(parameters: &Option<HashMap<String, String>>) -> Result<usize, EkError> {
+fn groups(rows_pr: f32) -> usize {
     let max_rows: usize = 50000;
     let max_instruments = 7000usize;
-    let max_group_size = match parameters {
-        Some(param) => match param.get("SDate") {
-            None => max_instruments,
-            Some(s_date) => {
-                let start_date = str_to_date(s_date.as_str())?;
-                let end_date = match param.get("EDate") {
-                    None => Utc::now().date_naive(),
-                    Some(value) => str_to_date(value.as_str())?,
-                };
-                let dur = end_date.signed_duration_since(start_date);
-                let frq = Frequency::new(param.get("Frq").unwrap_or(&String::from("d")).as_str());
-                let rows_pr = match frq {
-                    Frequency::Daily => dur.num_days() as f32,
-                    Frequency::Weekly => (dur.num_days() as f32) / 7f32,
-                    Frequency::Monthly => (dur.num_days() as f32) / 30f32,
-                    Frequency::Quarterly => (dur.num_days() as f32) / 90f32,
-                    Frequency::SemiAnnual => (dur.num_days() as f32) / 180f32,
-                    Frequency::Annual => (dur.num_days() as f32) / 365f32,
-                };
-                min(
-                    (max_rows as f32 / rows_pr).floor() as usize,
-                    max_instruments,
-                )
+    min(
+        (max_rows as f32 / rows_pr).floor() as usize,
+        max_instruments,
+    )
+}
+
+/// Rows per instrument of the field with the most, fields with their own parameters (e.g. a
+/// longer SDate) are counted with those on top of the request parameters
+fn field_rows(
+    fields: &Value,
+    parameters: &Option<HashMap<String, String>>,
+) -> Result<f32, EkError> {
+    let mut rows = rows_per_instrument(parameters)?;
+    let field_params = fields
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|f| f["parameters"].as_object());
+    for params in field_params {
+        let mut merged = parameters.clone().unwrap_or_default();
+        for (k, v) in params {
+            if let Some(v) = v.as_str() {
+                merged.insert(k.clone(), v.to_string());
             }
-        },
-        None => max_instruments,
-    };
-    Ok(max_group_size)
+        }
+        rows = rows.max(rows_per_instrument(&Some(merged))?);
+    }
+    Ok(rows)
+}
+
+/// Group size that keeps every field within the row limit
+fn field_groups(
+    fields: &Value,
+    parameters: &Option<HashMap<String, String>>,
+) -> Result<usize, EkError> {
+    Ok(groups(field_rows(fields, parameters)?).max(1))
 }
 
 fn fetch_headers(json_like: &Value, field_name: bool) -> Option<Vec<String>> {
@@ -151,10 +229,7 @@ fn fetch_headers(json_like: &Value, field_name: bool) -> Option<Vec<String>> {
     Some(names)
 }
 
-fn to_columns(
-    json_like: Vec<Value>,
-    field_name: bool,
-) -> Result<(Vec<String>, Vec<Vec<Option<String>>>), EkError> {
+fn to_columns(json_like: Vec<Value>, field_name: bool) -> Result<Table, EkError> {
     // Extract headers
     let mut headers: Vec<String> = Vec::new();
     for request in &json_like {
@@ -165,11 +240,14 @@ fn to_columns(
     }
 
     if headers.is_empty() {
-        return Err(EkError::NoHeaders("Could not build headers".to_string()));
+        return Err(EkError::new(
+            ErrorKind::NoHeaders,
+            "Could not build headers",
+        ));
     }
 
     // Extract data into columns
-    let mut columns: Vec<Vec<Option<String>>> = vec![Vec::new(); headers.len()];
+    let mut columns: Vec<Vec<Value>> = vec![Vec::new(); headers.len()];
 
     for request in &json_like {
         let rows = match request["responses"][0]["data"].as_array() {
@@ -178,12 +256,7 @@ fn to_columns(
         };
         for row in rows {
             for (col_idx, col) in columns.iter_mut().enumerate() {
-                let val = &row[col_idx];
-                if val.is_null() {
-                    col.push(None);
-                } else {
-                    col.push(Some(clean_string(val.to_string())));
-                }
+                col.push(row[col_idx].clone());
             }
         }
     }
@@ -193,8 +266,9 @@ fn to_columns(
 
 fn str_to_date(d: &str) -> Result<NaiveDate, EkError> {
     NaiveDate::parse_from_str(d, "%Y-%m-%d").map_err(|_| {
-        EkError::DateError(
-            "Could not parse date string, please supply ISO8601 format".to_string(),
+        EkError::new(
+            ErrorKind::Date,
+            "Could not parse date string, please supply ISO8601 format",
         )
     })
 }
@@ -0,0 +1,298 @@
+use crate::utils::{Column, EkError, ErrorKind};
+use arrow::array::{
+    Array, ArrayRef, BooleanArray, Date32Array, Float64Array, Int64Array, StringArray, StructArray,
+    TimestampMicrosecondArray, UInt32Array,
+};
+use arrow::compute::take;
+use arrow::datatypes::{DataType, Field, Schema, TimeUnit};
+use arrow::ffi::{FFI_ArrowArray, FFI_ArrowSchema};
+use arrow::record_batch::RecordBatch;
+use chrono::prelude::*;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Directory name hive-style readers use for rows without a partition value
+const NULL_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+/// Typed columns as an Arrow record batch. Dates become Date32 and timestamps microseconds
+/// in UTC, so readers get the same types as the R data.frame. Each column is freed as soon as
+/// its array is built, so the data is held twice for one column at most
+pub fn record_batch(names: &[String], columns: Vec<Column>) -> Result<RecordBatch, EkError> {
+    let fields: Vec<Field> = names
+        .iter()
+        .zip(&columns)
+        .map(|(name, column)| Field::new(name, data_type(column), true))
+        .collect();
+    let arrays: Vec<ArrayRef> = columns.into_iter().map(array).collect();
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays).map_err(arrow_error)
+}
+
+fn data_type(column: &Column) -> DataType {
+    match column {
+        Column::Float(_) => DataType::Float64,
+        Column::Int(_) => DataType::Int64,
+        Column::Bool(_) => DataType::Boolean,
+        Column::Date(_) => DataType::Date32,
+        Column::DateTime(_) => DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+        Column::Str(_) => DataType::Utf8,
+    }
+}
+
+fn array(column: Column) -> ArrayRef {
+    match column {
+        Column::Float(v) => Arc::new(Float64Array::from(v)),
+        Column::Int(v) => Arc::new(Int64Array::from(v)),
+        Column::Bool(v) => Arc::new(BooleanArray::from(v)),
+        Column::Date(v) => Arc::new(
+            v.into_iter()
+                .map(|d| d.map(|d| (d - NaiveDate::default()).num_days() as i32))
+                .collect::<Date32Array>(),
+        ),
+        Column::DateTime(v) => Arc::new(
+            v.into_iter()
+                .map(|t| t.map(|t| t.and_utc().timestamp_micros()))
+                .collect::<TimestampMicrosecondArray>()
+                .with_timezone("UTC"),
+        ),
+        Column::Str(v) => Arc::new(StringArray::from_iter(v)),
+    }
+}
+
+/// Moves the columns into Arrow C data interface structs on the heap and returns their
+/// addresses. The importer takes over the data, the structs are freed with `release_ffi`
+pub fn to_ffi(
+    names: &[String],
+    columns: Vec<Column>,
+) -> Result<(*mut FFI_ArrowArray, *mut FFI_ArrowSchema), EkError> {
+    let batch = record_batch(names, columns)?;
+    let data = StructArray::from(batch).into_data();
+    let (array, schema) = arrow::ffi::to_ffi(&data).map_err(arrow_error)?;
+    Ok((
+        Box::into_raw(Box::new(array)),
+        Box::into_raw(Box::new(schema)),
+    ))
+}
+
+/// Frees the structs returned by `to_ffi`, and the data too if it was never imported
+///
+/// # Safety
+/// Both pointers must come from a single call of `to_ffi` and not have been freed before
+pub unsafe fn release_ffi(array: *mut FFI_ArrowArray, schema: *mut FFI_ArrowSchema) {
+    drop(Box::from_raw(array));
+    drop(Box::from_raw(schema));
+}
+
+/// Writes the columns to Parquet and returns the files written. Without `partition_by` a
+/// single file is written to `path`, otherwise `path` is a directory with one hive-style
+/// subdirectory per value of that column (`RIC=IBM.N/part-0.parquet`). Date and timestamp
+/// columns are split by day into `<column>_date=2024-01-31` and kept in the files, other
+/// partition columns are left out of the files as readers restore them from the directory
+pub fn write_parquet(
+    path: &Path,
+    names: &[String],
+    columns: Vec<Column>,
+    partition_by: Option<&str>,
+) -> Result<Vec<PathBuf>, EkError> {
+    let Some(partition_by) = partition_by else {
+        write_file(path, &record_batch(names, columns)?)?;
+        return Ok(vec![path.to_path_buf()]);
+    };
+
+    let Some(i) = names.iter().position(|n| n == partition_by) else {
+        let msg = format!("Cannot partition by {partition_by}, there is no such column");
+        return Err(EkError::new(ErrorKind::Error, msg));
+    };
+    let (key, values, drop) = match &columns[i] {
+        Column::Date(v) => (
+            format!("{partition_by}_date"),
+            v.iter().map(|d| d.map(|d| d.to_string())).collect(),
+            false,
+        ),
+        Column::DateTime(v) => (
+            format!("{partition_by}_date"),
+            v.iter().map(|t| t.map(|t| t.date().to_string())).collect(),
+            false,
+        ),
+        other => (partition_by.to_string(), partition_values(other), true),
+    };
+    let batch = record_batch(names, columns)?;
+    let batch = match drop {
+        true => {
+            let mut batch = batch;
+            batch.remove_column(i);
+            batch
+        }
+        false => batch,
+    };
+
+    let mut partitions: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    for (row, value) in values.into_iter().enumerate() {
+        let dir = match value {
+            Some(v) if !v.is_empty() => escape(&v),
+            _ => NULL_PARTITION.to_string(),
+        };
+        partitions.entry(dir).or_default().push(row as u32);
+    }
+
+    let mut files = Vec::with_capacity(partitions.len());
+    for (value, rows) in partitions {
+        let indices = UInt32Array::from(rows);
+        let arrays = batch
+            .columns()
+            .iter()
+            .map(|c| take(c, &indices, None))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(arrow_error)?;
+        let part = RecordBatch::try_new(batch.schema(), arrays).map_err(arrow_error)?;
+        let file = path.join(format!("{key}={value}")).join("part-0.parquet");
+        write_file(&file, &part)?;
+        files.push(file);
+    }
+    Ok(files)
+}
+
+fn partition_values(column: &Column) -> Vec<Option<String>> {
+    match column {
+        Column::Float(v) => v.iter().map(|x| x.map(|x| x.to_string())).collect(),
+        Column::Int(v) => v.iter().map(|x| x.map(|x| x.to_string())).collect(),
+        Column::Bool(v) => v.iter().map(|x| x.map(|x| x.to_string())).collect(),
+        Column::Str(v) => v.clone(),
+        Column::Date(_) | Column::DateTime(_) => Vec::new(),
+    }
+}
+
+/// Percent-encodes everything but letters, digits and `-_.` so any value is a valid directory
+/// name, readers decode it again
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => escaped.push(b as char),
+            _ => escaped.push_str(&format!("%{b:02X}")),
+        }
+    }
+    escaped
+}
+
+fn write_file(path: &Path, batch: &RecordBatch) -> Result<(), EkError> {
+    let io_error = |e: std::io::Error| {
+        let msg = format!("Could not write {}: {e}", path.display());
+        EkError::new(ErrorKind::Error, msg)
+    };
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(io_error)?;
+    }
+    let file = File::create(path).map_err(io_error)?;
+    let props = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let parquet_error = |e: parquet::errors::ParquetError| {
+        let msg = format!("Could not write {}: {e}", path.display());
+        EkError::new(ErrorKind::Error, msg)
+    };
+    let mut writer =
+        ArrowWriter::try_new(file, batch.schema(), Some(props)).map_err(parquet_error)?;
+    writer.write(batch).map_err(parquet_error)?;
+    writer.close().map_err(parquet_error)?;
+    Ok(())
+}
+
+fn arrow_error(e: arrow::error::ArrowError) -> EkError {
+    EkError::new(ErrorKind::Error, format!("Could not build Arrow data: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    #[test]
+    fn test_write_parquet() {
+        let dir = std::env::temp_dir().join(format!("ek_parquet_{}", std::process::id()));
+        let names = ["TIMESTAMP", "CLOSE", "RIC"].map(String::from).to_vec();
+        let t = |s: &str| NaiveDateTime::parse_from_str(s, "%F %T").ok();
+        let columns = vec![
+            Column::DateTime(vec![
+                t("2024-05-30 00:00:00"),
+                t("2024-05-31 00:00:00"),
+                t("2024-05-31 00:00:00"),
+            ]),
+            Column::Float(vec![Some(1.0), None, Some(3.0)]),
+            Column::Str(vec![
+                Some("IBM.N".to_string()),
+                Some("IBM.N".to_string()),
+                Some("EUR=".to_string()),
+            ]),
+        ];
+
+        let file = dir.join("all.parquet");
+        assert_eq!(
+            write_parquet(&file, &names, columns.clone(), None).unwrap(),
+            vec![file.clone()]
+        );
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&file).unwrap()).unwrap();
+        let schema = reader.schema().clone();
+        assert_eq!(schema.field(0).data_type(), &data_type(&columns[0]));
+        let rows: usize = reader.build().unwrap().map(|b| b.unwrap().num_rows()).sum();
+        assert_eq!(rows, 3);
+
+        let files =
+            write_parquet(&dir.join("by_ric"), &names, columns.clone(), Some("RIC")).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                dir.join("by_ric").join("RIC=EUR%3D").join("part-0.parquet"),
+                dir.join("by_ric").join("RIC=IBM.N").join("part-0.parquet"),
+            ]
+        );
+        let reader =
+            ParquetRecordBatchReaderBuilder::try_new(File::open(&files[1]).unwrap()).unwrap();
+        assert_eq!(reader.schema().fields().len(), 2);
+
+        let files = write_parquet(
+            &dir.join("by_day"),
+            &names,
+            columns.clone(),
+            Some("TIMESTAMP"),
+        )
+        .unwrap();
+        assert_eq!(files.len(), 2);
+        assert!(files[0]
+            .to_string_lossy()
+            .contains("TIMESTAMP_date=2024-05-30"));
+        assert!(write_parquet(&dir.join("x"), &names, columns, Some("VOLUME")).is_err());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_to_ffi() {
+        let names = ["Date", "Value"].map(String::from).to_vec();
+        let columns = vec![
+            Column::Date(vec![NaiveDate::from_ymd_opt(1970, 1, 2), None]),
+            Column::Int(vec![Some(1), Some(2)]),
+        ];
+        let (array, schema) = to_ffi(&names, columns).unwrap();
+        let data = unsafe {
+            let imported = std::ptr::replace(array, FFI_ArrowArray::empty());
+            let data = arrow::ffi::from_ffi(imported, &*schema).unwrap();
+            release_ffi(array, schema);
+            data
+        };
+        let batch = RecordBatch::from(StructArray::from(data));
+        assert_eq!(batch.schema().field(0).name(), "Date");
+        assert_eq!(batch.num_rows(), 2);
+        let dates = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<Date32Array>()
+            .unwrap();
+        assert_eq!(dates.value(0), 1);
+        assert!(dates.is_null(1));
+    }
+}
@@ -1,64 +1,549 @@
 #![allow(non_snake_case)]
 
-use crate::connection::Connection;
+use crate::cache::{Checkpoint, ResponseCache};
+use crate::catalogue::FieldCatalogue;
+use crate::chain::Chain;
+use crate::connection::{
+    Connection, ConnectionConfig, DiscoveryReport, Progress, ProgressCallback, RateGovernor,
+    RateLimits, RetryPolicy, Scheme, TicketPolling,
+};
 use crate::datagrid::Datagrid;
+use crate::news::News;
+use crate::plan::RequestPlan;
+use crate::symbology::{SymbolType, Symbology};
 use crate::timeseries::{Interval, TimeSeries};
-use crate::utils::{EkError, EkResults, field_builder, Fields};
+use crate::utils::{field_builder, Column, EkError, EkResults, ErrorKind, FailedChunk, Fields};
 use chrono::prelude::*;
 use extendr_api::prelude::*;
 use std::collections::HashMap;
+use std::os::raw::{c_int, c_void};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+mod cache;
+mod catalogue;
+mod chain;
 mod connection;
 mod datagrid;
+mod export;
+mod news;
+mod plan;
+mod symbology;
 mod timeseries;
 mod utils;
 
 #[extendr]
+#[allow(clippy::too_many_arguments)]
 fn rust_get_dg(
     instruments: Vec<String>,
     fields: Vec<String>,
+    field_params: List,
     param: List,
     settings: List,
+    retry: List,
+    progress: Robj,
+    checkpoint: &str,
+    write_to: &str,
+    partition_by: &str,
+    as_arrow: bool,
     api: String,
-    port: i32,
+    connection: List,
 ) -> Robj {
-    let con = Connection::new(api, "127.0.0.1".to_string(), port as i16);
+    if let Err(e) = check_output(write_to, as_arrow) {
+        return error_condition(e);
+    }
+    let con = match connection_config(api, &connection).and_then(Connection::new) {
+        Ok(c) => c
+            .with_ticket_polling(ticket_polling(&settings))
+            .with_retry_policy(retry_policy(&retry, RetryPolicy::global()))
+            .with_progress(progress_callback(progress))
+            .with_checkpoint(checkpoint_dir(checkpoint))
+            .with_interrupt(r_interrupted),
+        Err(e) => return error_condition(e),
+    };
     let dg = Datagrid::new(con);
     let params = list_to_hm_string(&param);
     let settings_map = list_to_hm_bool(&settings);
-    let fields_json = field_builder(Fields::NoParams(fields));
+    let fields_json = match datagrid_fields(fields, &field_params) {
+        Ok(f) => f,
+        Err(e) => return error_condition(e),
+    };
+
+    let res = dg.get_datagrid(instruments, fields_json, Some(params), settings_map);
+    output(res, write_to, partition_by, as_arrow)
+}
 
-    match dg.get_datagrid(instruments, fields_json, Some(params), settings_map) {
-        EkResults::Columns { names, columns } => columns_to_r_list(&names, columns),
-        EkResults::Raw(r) => value_strings(r).into_robj(),
-        EkResults::Err(e) => vec!["Error".to_string(), e.to_string()].into_robj(),
+#[extendr]
+fn rust_plan_dg(
+    instruments: Vec<String>,
+    fields: Vec<String>,
+    field_params: List,
+    param: List,
+) -> Robj {
+    let fields_json = match datagrid_fields(fields, &field_params) {
+        Ok(f) => f,
+        Err(e) => return error_condition(e),
+    };
+    let params = Some(list_to_hm_string(&param));
+    match Datagrid::plan(&instruments, &fields_json, &params) {
+        Ok(plan) => plan_to_list(plan).into_robj(),
+        Err(e) => error_condition(e),
     }
 }
 
 #[extendr]
+#[allow(clippy::too_many_arguments)]
 fn rust_get_ts(
     rics: Vec<String>,
     fields: Vec<String>,
     Frq: &str,
     Start_Date: &str,
     End_Date: &str,
+    retry: List,
+    progress: Robj,
+    checkpoint: &str,
+    write_to: &str,
+    partition_by: &str,
+    as_arrow: bool,
     api: String,
-    port: i32,
+    connection: List,
 ) -> Robj {
-    let con = Connection::new(api, "127.0.0.1".to_string(), port as i16);
+    if let Err(e) = check_output(write_to, as_arrow) {
+        return error_condition(e);
+    }
+    let con = match connection_config(api, &connection).and_then(Connection::new) {
+        Ok(c) => c
+            .with_retry_policy(retry_policy(&retry, RetryPolicy::global()))
+            .with_progress(progress_callback(progress))
+            .with_checkpoint(checkpoint_dir(checkpoint))
+            .with_interrupt(r_interrupted),
+        Err(e) => return error_condition(e),
+    };
     let ts = TimeSeries::new(con);
 
-    let s_date = match NaiveDateTime::parse_from_str(Start_Date, "%FT%T") {
+    let (s_date, e_date) = match ts_dates(Start_Date, End_Date) {
         Ok(d) => d,
-        Err(e) => return vec!["Error".to_string(), format!("Cannot parse start_date: {e}")].into_robj(),
+        Err(e) => return error_condition(e),
     };
 
-    let e_date = match NaiveDateTime::parse_from_str(End_Date, "%FT%T") {
+    let res = ts.get_timeseries(rics, fields, interval(Frq), s_date, e_date);
+    output(res, write_to, partition_by, as_arrow)
+}
+
+/// Timeseries for the bars after `last_times`, the latest timestamp held for each of
+/// `last_rics`. RICs without one are requested from Start_Date
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn rust_update_ts(
+    rics: Vec<String>,
+    fields: Vec<String>,
+    Frq: &str,
+    last_rics: Vec<String>,
+    last_times: Vec<String>,
+    Start_Date: &str,
+    End_Date: &str,
+    retry: List,
+    progress: Robj,
+    checkpoint: &str,
+    api: String,
+    connection: List,
+) -> Robj {
+    let (s_date, e_date) = match ts_dates(Start_Date, End_Date) {
         Ok(d) => d,
-        Err(e) => return vec!["Error".to_string(), format!("Cannot parse end_date: {e}")].into_robj(),
+        Err(e) => return error_condition(e),
+    };
+    let mut last = HashMap::with_capacity(last_rics.len());
+    for (ric, t) in last_rics.into_iter().zip(last_times) {
+        match NaiveDateTime::parse_from_str(&t, "%FT%T") {
+            Ok(t) => last.insert(ric, t),
+            Err(e) => {
+                let msg = format!("Cannot parse the last timestamp of {ric}: {e}");
+                return error_condition(EkError::new(ErrorKind::Date, msg));
+            }
+        };
+    }
+
+    let con = match connection_config(api, &connection).and_then(Connection::new) {
+        Ok(c) => c
+            .with_retry_policy(retry_policy(&retry, RetryPolicy::global()))
+            .with_progress(progress_callback(progress))
+            .with_checkpoint(checkpoint_dir(checkpoint))
+            .with_interrupt(r_interrupted),
+        Err(e) => return error_condition(e),
+    };
+    let ts = TimeSeries::new(con);
+
+    results_to_robj(ts.update(rics, fields, interval(Frq), last, s_date, e_date))
+}
+
+/// Frees the structs made for an Arrow handoff, once arrow has imported them or failed to
+#[extendr]
+fn rust_release_arrow(array: f64, schema: f64) {
+    // Safety: the addresses are the ones arrow_to_robj returned, released exactly once by R
+    unsafe {
+        export::release_ffi(array as usize as *mut _, schema as usize as *mut _);
+    }
+}
+
+#[extendr]
+fn rust_plan_ts(
+    rics: Vec<String>,
+    fields: Vec<String>,
+    Frq: &str,
+    Start_Date: &str,
+    End_Date: &str,
+) -> Robj {
+    match ts_dates(Start_Date, End_Date) {
+        Ok((s_date, e_date)) => {
+            let plan = TimeSeries::plan(&rics, &fields, interval(Frq), s_date, e_date);
+            plan_to_list(plan).into_robj()
+        }
+        Err(e) => error_condition(e),
+    }
+}
+
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn rust_get_news_headlines(
+    query: &str,
+    date_from: &str,
+    date_to: &str,
+    repository: Vec<String>,
+    count: i32,
+    retry: List,
+    api: String,
+    connection: List,
+) -> Robj {
+    let con = match connection_config(api, &connection).and_then(Connection::new) {
+        Ok(c) => c
+            .with_retry_policy(retry_policy(&retry, RetryPolicy::global()))
+            .with_interrupt(r_interrupted),
+        Err(e) => return error_condition(e),
+    };
+    let news = News::new(con);
+
+    let mut dates = [None, None];
+    for (i, (name, d)) in [("date_from", date_from), ("date_to", date_to)]
+        .iter()
+        .enumerate()
+    {
+        if d.is_empty() {
+            continue;
+        }
+        match NaiveDateTime::parse_from_str(d, "%FT%T") {
+            Ok(d) => dates[i] = Some(d),
+            Err(e) => {
+                let msg = format!("Cannot parse {name}: {e}");
+                return error_condition(EkError::new(ErrorKind::Date, msg));
+            }
+        }
+    }
+
+    let count = count.max(0) as usize;
+    results_to_robj(news.get_headlines(query, dates[0], dates[1], &repository, count))
+}
+
+#[extendr]
+fn rust_get_news_story(story_ids: Vec<String>, retry: List, api: String, connection: List) -> Robj {
+    let con = match connection_config(api, &connection).and_then(Connection::new) {
+        Ok(c) => c
+            .with_retry_policy(retry_policy(&retry, RetryPolicy::global()))
+            .with_interrupt(r_interrupted),
+        Err(e) => return error_condition(e),
+    };
+    let news = News::new(con);
+
+    results_to_robj(news.get_stories(story_ids))
+}
+
+#[extendr]
+fn rust_get_symbology(
+    symbols: Vec<String>,
+    from: &str,
+    to: Vec<String>,
+    retry: List,
+    api: String,
+    connection: List,
+) -> Robj {
+    let con = match connection_config(api, &connection).and_then(Connection::new) {
+        Ok(c) => c
+            .with_retry_policy(retry_policy(&retry, RetryPolicy::global()))
+            .with_interrupt(r_interrupted),
+        Err(e) => return error_condition(e),
+    };
+    let symbology = Symbology::new(con);
+
+    let mut types = Vec::with_capacity(to.len() + 1);
+    for name in std::iter::once(from).chain(to.iter().map(|t| t.as_str())) {
+        match SymbolType::from_name(name) {
+            Some(t) => types.push(t),
+            None => {
+                let msg = format!("Unknown symbol type {name}");
+                return error_condition(EkError::new(ErrorKind::Error, msg));
+            }
+        }
+    }
+    let from = types.remove(0);
+
+    results_to_robj(symbology.convert(symbols, from, types))
+}
+
+#[extendr]
+fn rust_get_chain(
+    chains: Vec<String>,
+    as_of: &str,
+    retry: List,
+    api: String,
+    connection: List,
+) -> Robj {
+    let con = match connection_config(api, &connection).and_then(Connection::new) {
+        Ok(c) => c
+            .with_retry_policy(retry_policy(&retry, RetryPolicy::global()))
+            .with_interrupt(r_interrupted),
+        Err(e) => return error_condition(e),
+    };
+    let chain = Chain::new(Datagrid::new(con));
+
+    let as_of = match as_of {
+        "" => None,
+        d => match NaiveDate::parse_from_str(d, "%Y-%m-%d") {
+            Ok(d) => Some(d),
+            Err(e) => {
+                let msg = format!("Cannot parse as_of: {e}");
+                return error_condition(EkError::new(ErrorKind::Date, msg));
+            }
+        },
+    };
+
+    results_to_robj(chain.expand(chains, as_of))
+}
+
+#[extendr]
+fn rust_get_fields(
+    fields: Vec<String>,
+    instrument: &str,
+    retry: List,
+    api: String,
+    connection: List,
+) -> Robj {
+    let con = match connection_config(api, &connection).and_then(Connection::new) {
+        Ok(c) => c
+            .with_retry_policy(retry_policy(&retry, RetryPolicy::global()))
+            .with_interrupt(r_interrupted),
+        Err(e) => return error_condition(e),
+    };
+
+    results_to_robj(FieldCatalogue::describe(&con, fields, instrument))
+}
+
+/// Parameters of a datagrid request not known for its fields, nothing is sent
+#[extendr]
+fn rust_unknown_parameters(fields: Vec<String>, field_params: List, param: List) -> Robj {
+    let fields_json = match datagrid_fields(fields, &field_params) {
+        Ok(f) => f,
+        Err(e) => return error_condition(e),
+    };
+    let params = Some(list_to_hm_string(&param));
+    FieldCatalogue::unknown_parameters(&fields_json, &params).into_robj()
+}
+
+/// An empty dir keeps the field catalogue in memory only
+#[extendr]
+fn rust_set_field_cache(dir: &str) {
+    FieldCatalogue::set_cache_dir(Some(PathBuf::from(dir)).filter(|_| !dir.is_empty()));
+}
+
+#[extendr]
+fn rust_clear_field_cache() -> Robj {
+    match FieldCatalogue::clear() {
+        Ok(()) => NULL.into_robj(),
+        Err(e) => error_condition(e),
+    }
+}
+
+/// An empty dir disables the response cache, ttl is in seconds
+#[extendr]
+fn rust_set_cache(dir: &str, ttl: f64) {
+    let cache = match dir {
+        "" => None,
+        d => Some(ResponseCache::new(
+            PathBuf::from(d),
+            Duration::from_secs_f64(ttl.max(0.0)),
+        )),
+    };
+    ResponseCache::set_global(cache);
+}
+
+#[extendr]
+fn rust_get_cache() -> List {
+    let Some(cache) = ResponseCache::global() else {
+        return list!(enabled = false);
+    };
+    let entries = cache.entries();
+    let key: Vec<&str> = entries.iter().map(|e| e.key.as_str()).collect();
+    let direction: Vec<&str> = entries.iter().map(|e| e.direction.as_str()).collect();
+    let created = Column::DateTime(
+        entries
+            .iter()
+            .map(|e| Some(e.created.naive_utc()))
+            .collect(),
+    );
+    let expires = Column::DateTime(
+        entries
+            .iter()
+            .map(|e| e.expires.map(|t| t.naive_utc()))
+            .collect(),
+    );
+    let size: Vec<f64> = entries.iter().map(|e| e.bytes as f64).collect();
+    list!(
+        enabled = true,
+        dir = cache.dir.display().to_string(),
+        ttl = cache.ttl.as_secs_f64(),
+        entries = list!(
+            key = key,
+            direction = direction,
+            created = column_to_robj(created),
+            expires = column_to_robj(expires),
+            size = size
+        )
+    )
+}
+
+#[extendr]
+fn rust_clear_cache(expired_only: bool) -> Robj {
+    let Some(cache) = ResponseCache::global() else {
+        let msg = "The response cache is not enabled";
+        return error_condition(EkError::new(ErrorKind::Error, msg));
+    };
+    match cache.clear(expired_only) {
+        Ok(n) => (n as i32).into_robj(),
+        Err(e) => error_condition(e),
+    }
+}
+
+#[extendr]
+fn rust_set_rate_limits(per_second: i32, per_minute: i32, per_day: i32, mb_per_minute: f64) {
+    RateGovernor::set_limits(RateLimits {
+        per_second: per_second.max(1) as u32,
+        per_minute: per_minute.max(1) as u32,
+        per_day: per_day.max(1) as u32,
+        bytes_per_minute: (mb_per_minute.max(0.0) * 1024.0 * 1024.0) as u64,
+    });
+}
+
+#[extendr]
+fn rust_get_rate_limits() -> List {
+    let limits = RateGovernor::limits();
+    list!(
+        per_second = limits.per_second as i32,
+        per_minute = limits.per_minute as i32,
+        per_day = limits.per_day as i32,
+        mb_per_minute = limits.bytes_per_minute as f64 / (1024.0 * 1024.0)
+    )
+}
+
+#[extendr]
+fn rust_set_retry_policy(policy: List) {
+    RetryPolicy::set_global(retry_policy(&policy, RetryPolicy::default()));
+}
+
+#[extendr]
+fn rust_get_retry_policy() -> List {
+    retry_policy_to_list(&RetryPolicy::global())
+}
+
+#[extendr]
+fn rust_discover_port() -> Robj {
+    match Connection::discover() {
+        Ok(report) => discovery_to_list(report).into_robj(),
+        Err(e) => error_condition(e),
+    }
+}
+
+#[extendr]
+fn rust_proxy_status(port: i32) -> Robj {
+    match u16::try_from(port) {
+        Ok(p) => match Connection::status(p) {
+            Ok(up) => up.into_robj(),
+            Err(e) => error_condition(e),
+        },
+        Err(_) => false.into_robj(),
+    }
+}
+
+/// Field list of a datagrid request, with the parameters of every field named in field_params.
+/// Names are matched exactly, a name that is not among the fields or is given twice is an error
+fn datagrid_fields(
+    fields: Vec<String>,
+    field_params: &List,
+) -> std::result::Result<serde_json::Value, EkError> {
+    if field_params.is_empty() {
+        return Ok(field_builder(Fields::NoParams(fields)));
+    }
+    let mut by_name: HashMap<&str, Robj> = HashMap::new();
+    let mut duplicated: Vec<String> = Vec::new();
+    for (name, params) in field_params.iter() {
+        if by_name.insert(name, params).is_some() && !duplicated.iter().any(|d| d == name) {
+            duplicated.push(name.to_string());
+        }
+    }
+    if !duplicated.is_empty() {
+        let msg = format!(
+            "field_params given more than once for: {}",
+            duplicated.join(", ")
+        );
+        let mut e = EkError::new(ErrorKind::InvalidField, msg);
+        e.fields = duplicated;
+        return Err(e);
+    }
+    let mut unknown: Vec<String> = by_name
+        .keys()
+        .filter(|name| !fields.iter().any(|f| f == *name))
+        .map(|name| name.to_string())
+        .collect();
+    unknown.sort();
+    if !unknown.is_empty() {
+        let msg = format!(
+            "field_params given for fields not requested: {}",
+            unknown.join(", ")
+        );
+        let mut e = EkError::new(ErrorKind::InvalidField, msg);
+        e.fields = unknown;
+        return Err(e);
+    }
+
+    let with_params = fields
+        .into_iter()
+        .map(|f| {
+            let params = by_name
+                .get(f.as_str())
+                .and_then(|p| p.as_list())
+                .map(|l| list_to_hm_string(&l))
+                .unwrap_or_default();
+            (f, params)
+        })
+        .collect();
+    Ok(field_builder(Fields::Params(with_params)))
+}
+
+fn ts_dates(
+    start_date: &str,
+    end_date: &str,
+) -> std::result::Result<(NaiveDateTime, NaiveDateTime), EkError> {
+    let parse = |d: &str, name: &str| {
+        NaiveDateTime::parse_from_str(d, "%FT%T").map_err(|e| {
+            let msg = format!("Cannot parse {name}: {e}");
+            EkError::new(ErrorKind::Date, msg)
+        })
     };
+    Ok((
+        parse(start_date, "start_date")?,
+        parse(end_date, "end_date")?,
+    ))
+}
 
-    let interval = match Frq {
+fn interval(frq: &str) -> Interval {
+    match frq {
         "minute" => Interval::Minute,
         "hour" => Interval::Hour,
         "daily" => Interval::Daily,
@@ -67,15 +552,65 @@ fn rust_get_ts(
         "quarterly" => Interval::Quarterly,
         "yearly" => Interval::Yearly,
         _ => Interval::Daily,
-    };
-
-    match ts.get_timeseries(rics, fields, interval, s_date, e_date) {
-        EkResults::Columns { names, columns } => columns_to_r_list(&names, columns),
-        EkResults::Raw(r) => value_strings(r).into_robj(),
-        EkResults::Err(e) => vec!["Error".to_string(), e.to_string()].into_robj(),
     }
 }
 
+/// An empty dir disables checkpoints
+fn checkpoint_dir(dir: &str) -> Option<Checkpoint> {
+    Some(Checkpoint::new(PathBuf::from(dir))).filter(|_| !dir.is_empty())
+}
+
+extern "C" {
+    fn R_ToplevelExec(fun: Option<unsafe extern "C" fn(*mut c_void)>, data: *mut c_void) -> c_int;
+    fn R_CheckUserInterrupt();
+}
+
+unsafe extern "C" fn check_user_interrupt(_: *mut c_void) {
+    R_CheckUserInterrupt();
+}
+
+/// Whether the user pressed Ctrl-C or Esc since the last check. `R_CheckUserInterrupt` jumps
+/// out to the top level on an interrupt, `R_ToplevelExec` catches that jump and returns false
+fn r_interrupted() -> bool {
+    unsafe { R_ToplevelExec(Some(check_user_interrupt), std::ptr::null_mut()) == 0 }
+}
+
+/// Calls the R function `f` with a list of the progress fields, times in seconds. Errors in
+/// `f` are ignored so a broken callback cannot stop a download
+fn progress_callback(f: Robj) -> Option<ProgressCallback> {
+    let f = f.as_function()?;
+    Some(Box::new(move |p: &Progress| {
+        let _ = f.call(pairlist!(progress_to_list(p)));
+    }))
+}
+
+fn progress_to_list(p: &Progress) -> List {
+    list!(
+        total = p.total as i32,
+        completed = p.completed as i32,
+        failed = p.failed as i32,
+        retrying = p.retrying as i32,
+        rows = p.rows as f64,
+        elapsed = p.elapsed.as_secs_f64(),
+        eta = p.eta.map(|d| d.as_secs_f64())
+    )
+}
+
+/// The payloads of a plan as a data.frame-compatible list, with the totals and the time the
+/// current rate limits need for them
+fn plan_to_list(plan: RequestPlan) -> List {
+    let limits = RateGovernor::limits();
+    let (names, columns) = plan.table();
+    list!(
+        payloads = columns_to_r_list(&names, columns),
+        n_payloads = plan.payloads.len() as i32,
+        rows = plan.rows() as f64,
+        mb = plan.bytes() as f64 / (1024.0 * 1024.0),
+        seconds = plan.estimated_time(&limits).as_secs_f64(),
+        exceeds_daily_limit = plan.exceeds_daily_limit(&limits)
+    )
+}
+
 fn list_to_hm_string(l: &List) -> HashMap<String, String> {
     let mut params: HashMap<String, String> = HashMap::new();
     for (key, value) in l.iter() {
@@ -96,12 +631,355 @@ fn list_to_hm_bool(l: &List) -> HashMap<String, bool> {
     params
 }
 
-/// Convert columnar data into a named R list (data.frame-compatible)
-fn columns_to_r_list(names: &[String], columns: Vec<Vec<Option<String>>>) -> Robj {
-    let values: Vec<Robj> = columns
-        .into_iter()
-        .map(|col| {
-            let strs: Strings = col
+/// Reads host, port, scheme, base_path and headers from the connection settings
+fn connection_config(api: String, l: &List) -> std::result::Result<ConnectionConfig, EkError> {
+    let mut config = ConnectionConfig::new(api);
+    for (name, value) in l.iter() {
+        let invalid = || {
+            EkError::new(
+                ErrorKind::Error,
+                format!("Invalid connection setting {name}"),
+            )
+        };
+        config = match name {
+            "host" => config.host(value.as_str().ok_or_else(invalid)?),
+            "port" => {
+                let port = r_number(&value)
+                    .filter(|p| p.fract() == 0.0 && *p >= 1.0 && *p <= f64::from(u16::MAX))
+                    .ok_or_else(invalid)?;
+                config.port(port as u16)
+            }
+            "scheme" => match value.as_str() {
+                Some("http") => config.scheme(Scheme::Http),
+                Some("https") => config.scheme(Scheme::Https),
+                _ => return Err(invalid()),
+            },
+            "base_path" => config.base_path(value.as_str().ok_or_else(invalid)?),
+            "headers" => {
+                let headers = value.as_list().ok_or_else(invalid)?;
+                for (header, v) in headers.iter() {
+                    config = config.header(header, v.as_str().ok_or_else(invalid)?);
+                }
+                config
+            }
+            _ => config,
+        };
+    }
+    Ok(config)
+}
+
+/// Reads the optional ticket_* settings on top of the default polling setup. Delays are in
+/// seconds, ticket_backoff is the multiplier applied between polls.
+fn ticket_polling(settings: &List) -> TicketPolling {
+    let mut polling = TicketPolling::default();
+    for (key, value) in settings.iter() {
+        let Some(secs) = r_number(&value) else {
+            continue;
+        };
+        match key {
+            "ticket_initial_delay" => polling.initial_delay = Duration::from_secs_f64(secs),
+            "ticket_max_delay" => polling.max_delay = Duration::from_secs_f64(secs),
+            "ticket_max_wait" => polling.max_wait = Duration::from_secs_f64(secs),
+            "ticket_backoff" => polling.multiplier = secs,
+            _ => {}
+        }
+    }
+    polling
+}
+
+/// Applies the entries of an R list on top of `policy`. Delays and the deadline are in
+/// seconds, a NULL deadline removes it.
+fn retry_policy(l: &List, mut policy: RetryPolicy) -> RetryPolicy {
+    for (key, value) in l.iter() {
+        match key {
+            "retry_statuses" => {
+                if let Some(v) = r_numbers(&value) {
+                    policy.retry_statuses = v.into_iter().map(|n| n as u16).collect();
+                }
+            }
+            "retry_error_codes" => {
+                if let Some(v) = r_numbers(&value) {
+                    policy.retry_error_codes = v.into_iter().map(|n| n as i64).collect();
+                }
+            }
+            "deadline" if value.is_null() => policy.deadline = None,
+            _ => {
+                let Some(n) = r_number(&value) else {
+                    continue;
+                };
+                match key {
+                    "max_attempts" => policy.max_attempts = (n as u32).max(1),
+                    "initial_delay" => policy.initial_delay = Duration::from_secs_f64(n),
+                    "max_delay" => policy.max_delay = Duration::from_secs_f64(n),
+                    "backoff" => policy.multiplier = n,
+                    "jitter" => policy.jitter = n.min(1.0),
+                    "deadline" => policy.deadline = Some(Duration::from_secs_f64(n)),
+                    _ => {}
+                }
+            }
+        }
+    }
+    policy
+}
+
+fn retry_policy_to_list(policy: &RetryPolicy) -> List {
+    let statuses: Vec<i32> = policy
+        .retry_statuses
+        .iter()
+        .map(|&s| i32::from(s))
+        .collect();
+    let codes: Vec<f64> = policy.retry_error_codes.iter().map(|&c| c as f64).collect();
+    list!(
+        max_attempts = policy.max_attempts as i32,
+        initial_delay = policy.initial_delay.as_secs_f64(),
+        max_delay = policy.max_delay.as_secs_f64(),
+        backoff = policy.multiplier,
+        jitter = policy.jitter,
+        retry_statuses = statuses,
+        retry_error_codes = codes,
+        deadline = policy.deadline.map(|d| d.as_secs_f64())
+    )
+}
+
+fn discovery_to_list(report: DiscoveryReport) -> List {
+    let path: Vec<String> = report
+        .port_files
+        .iter()
+        .map(|f| f.path.display().to_string())
+        .collect();
+    let exists: Vec<bool> = report.port_files.iter().map(|f| f.exists).collect();
+    let port: Vec<Option<i32>> = report
+        .port_files
+        .iter()
+        .map(|f| f.port.map(i32::from))
+        .collect();
+    let responding: Vec<bool> = report.port_files.iter().map(|f| f.responding).collect();
+    let probed: Vec<i32> = report.probed.into_iter().map(i32::from).collect();
+    list!(
+        port = report.port.map(i32::from),
+        port_files = list!(
+            path = path,
+            exists = exists,
+            port = port,
+            responding = responding
+        ),
+        probed = probed
+    )
+}
+
+/// A single finite, non-negative number
+fn r_number(v: &Robj) -> Option<f64> {
+    v.as_real()
+        .or_else(|| v.as_integer().map(f64::from))
+        .filter(|n| n.is_finite() && *n >= 0.0)
+}
+
+fn r_numbers(v: &Robj) -> Option<Vec<f64>> {
+    v.as_real_vector().or_else(|| {
+        v.as_integer_vector()
+            .map(|v| v.into_iter().map(f64::from).collect())
+    })
+}
+
+/// A result is either returned as Arrow or written to Parquet, never both
+fn check_output(write_to: &str, as_arrow: bool) -> std::result::Result<(), EkError> {
+    match as_arrow && !write_to.is_empty() {
+        true => Err(EkError::new(
+            ErrorKind::Error,
+            "as_arrow and write_to cannot be combined",
+        )),
+        false => Ok(()),
+    }
+}
+
+/// Results as R objects, or written to Parquet at `write_to` when it is not empty, in which
+/// case the paths of the files written are returned. With `as_arrow` the columns are handed
+/// over through the Arrow C data interface instead
+fn output(res: EkResults, write_to: &str, partition_by: &str, as_arrow: bool) -> Robj {
+    if as_arrow {
+        return arrow_to_robj(res);
+    }
+    if write_to.is_empty() {
+        return results_to_robj(res);
+    }
+    match res {
+        EkResults::Columns {
+            names,
+            columns,
+            failed,
+        } => {
+            let partition_by = Some(partition_by).filter(|p| !p.is_empty());
+            match export::write_parquet(Path::new(write_to), &names, columns, partition_by) {
+                Ok(files) => {
+                    let files: Vec<String> = files
+                        .iter()
+                        .map(|f| f.to_string_lossy().into_owned())
+                        .collect();
+                    with_failed_chunks(files.into_robj(), failed)
+                }
+                Err(e) => error_condition(e),
+            }
+        }
+        EkResults::Raw { .. } => error_condition(EkError::new(
+            ErrorKind::Error,
+            "Raw results cannot be written to Parquet",
+        )),
+        EkResults::Err(e) => error_condition(e),
+    }
+}
+
+/// Addresses of the Arrow C data interface structs holding the columns, as doubles so that
+/// arrow::RecordBatch$import_from_c() accepts them. R frees them with rust_release_arrow()
+fn arrow_to_robj(res: EkResults) -> Robj {
+    match res {
+        EkResults::Columns {
+            names,
+            columns,
+            failed,
+        } => match export::to_ffi(&names, columns) {
+            Ok((array, schema)) => {
+                let ptrs = list!(
+                    array = array as usize as f64,
+                    schema = schema as usize as f64
+                );
+                with_failed_chunks(ptrs.into_robj(), failed)
+            }
+            Err(e) => error_condition(e),
+        },
+        EkResults::Raw { .. } => error_condition(EkError::new(
+            ErrorKind::Error,
+            "Raw results cannot be returned as Arrow",
+        )),
+        EkResults::Err(e) => error_condition(e),
+    }
+}
+
+fn results_to_robj(res: EkResults) -> Robj {
+    match res {
+        EkResults::Columns {
+            names,
+            columns,
+            failed,
+        } => with_failed_chunks(columns_to_r_list(&names, columns), failed),
+        EkResults::Raw { data, failed } => {
+            with_failed_chunks(value_strings(data).into_robj(), failed)
+        }
+        EkResults::Err(e) => error_condition(e),
+    }
+}
+
+/// Converts an error into an R condition object of class
+/// c("<kind>", "ek_error", "error", "condition"), signalled by the R wrappers with stop()
+fn error_condition(e: EkError) -> Robj {
+    let mut classes = vec![e.kind.condition_class()];
+    if e.kind != ErrorKind::Error {
+        classes.push("ek_error");
+    }
+    classes.extend(["error", "condition"]);
+
+    let mut cond = list!(
+        message = e.to_string(),
+        call = NULL,
+        error_code = e.error_code.and_then(|c| i32::try_from(c).ok()),
+        status = e.status.map(i32::from),
+        instruments = e.instruments,
+        fields = e.fields,
+        retryable = e.retryable
+    )
+    .into_robj();
+    let _ = cond.set_class(classes);
+    cond
+}
+
+/// Attach the chunks that could not be downloaded as a data.frame-compatible
+/// "failed_chunks" attribute
+fn with_failed_chunks(mut robj: Robj, failed: Vec<FailedChunk>) -> Robj {
+    if failed.is_empty() {
+        return robj;
+    }
+    let payload: Vec<String> = failed.iter().map(|f| f.payload.to_string()).collect();
+    let status: Vec<Option<i32>> = failed
+        .iter()
+        .map(|f| f.error.status.map(i32::from))
+        .collect();
+    let error_code: Vec<Option<i32>> = failed
+        .iter()
+        .map(|f| f.error.error_code.and_then(|c| i32::try_from(c).ok()))
+        .collect();
+    let attempts: Vec<i32> = failed.iter().map(|f| f.attempts as i32).collect();
+    let class: Vec<&str> = failed
+        .iter()
+        .map(|f| f.error.kind.condition_class())
+        .collect();
+    let message: Vec<String> = failed.iter().map(|f| f.error.to_string()).collect();
+
+    let chunks = list!(
+        payload = payload,
+        status = status,
+        error_code = error_code,
+        attempts = attempts,
+        class = class,
+        message = message
+    );
+    let _ = robj.set_attrib("failed_chunks", chunks);
+    robj
+}
+
+/// Convert columnar data into a named R list (data.frame-compatible), dates become Date and
+/// timestamps POSIXct in UTC
+fn columns_to_r_list(names: &[String], columns: Vec<Column>) -> Robj {
+    let values: Vec<Robj> = columns.into_iter().map(column_to_robj).collect();
+
+    let name_strs: Vec<&str> = names.iter().map(|s| s.as_str()).collect();
+    match List::from_names_and_values(name_strs, values) {
+        Ok(list) => list.into_robj(),
+        Err(_) => error_condition(EkError::new(ErrorKind::Error, "Could not build named list")),
+    }
+}
+
+fn column_to_robj(column: Column) -> Robj {
+    match column {
+        Column::Float(v) => v.into_robj(),
+        Column::Int(v) => {
+            let ints: Option<Vec<Option<i32>>> = v
+                .iter()
+                .map(|x| match x {
+                    Some(i) => i32::try_from(*i).ok().filter(|i| *i != i32::MIN).map(Some),
+                    None => Some(None),
+                })
+                .collect();
+            match ints {
+                Some(ints) => ints.into_robj(),
+                None => v
+                    .into_iter()
+                    .map(|x| x.map(|i| i as f64))
+                    .collect::<Vec<_>>()
+                    .into_robj(),
+            }
+        }
+        Column::Bool(v) => v.into_robj(),
+        Column::Date(v) => {
+            let epoch = NaiveDate::default();
+            let days: Vec<Option<f64>> = v
+                .into_iter()
+                .map(|d| d.map(|d| d.signed_duration_since(epoch).num_days() as f64))
+                .collect();
+            let mut robj = days.into_robj();
+            let _ = robj.set_class(["Date"]);
+            robj
+        }
+        Column::DateTime(v) => {
+            let secs: Vec<Option<f64>> = v
+                .into_iter()
+                .map(|t| t.map(|t| t.and_utc().timestamp_millis() as f64 / 1000.0))
+                .collect();
+            let mut robj = secs.into_robj();
+            let _ = robj.set_class(["POSIXct", "POSIXt"]);
+            let _ = robj.set_attrib("tzone", "UTC");
+            robj
+        }
+        Column::Str(v) => {
+            let strs: Strings = v
                 .into_iter()
                 .map(|v| match v {
                     Some(s) => Rstr::from(s),
@@ -109,13 +987,7 @@ fn columns_to_r_list(names: &[String], columns: Vec<Vec<Option<String>>>) -> Rob
                 })
                 .collect();
             strs.into_robj()
-        })
-        .collect();
-
-    let name_strs: Vec<&str> = names.iter().map(|s| s.as_str()).collect();
-    match List::from_names_and_values(name_strs, values) {
-        Ok(list) => list.into_robj(),
-        Err(_) => vec!["Error".to_string(), "Could not build named list".to_string()].into_robj(),
+        }
     }
 }
 
@@ -126,5 +998,26 @@ fn value_strings(v: Vec<serde_json::Value>) -> Vec<String> {
 extendr_module! {
     mod EikonDownloader;
     fn rust_get_dg;
+    fn rust_plan_dg;
     fn rust_get_ts;
+    fn rust_update_ts;
+    fn rust_release_arrow;
+    fn rust_plan_ts;
+    fn rust_get_news_headlines;
+    fn rust_get_news_story;
+    fn rust_get_symbology;
+    fn rust_get_chain;
+    fn rust_get_fields;
+    fn rust_unknown_parameters;
+    fn rust_set_field_cache;
+    fn rust_clear_field_cache;
+    fn rust_set_cache;
+    fn rust_get_cache;
+    fn rust_clear_cache;
+    fn rust_set_rate_limits;
+    fn rust_get_rate_limits;
+    fn rust_set_retry_policy;
+    fn rust_get_retry_policy;
+    fn rust_discover_port;
+    fn rust_proxy_status;
 }
@@ -0,0 +1,367 @@
+use crate::connection::{Connection, Direction};
+use crate::utils::{parse_datetime, ChunkResults, Column, ColumnType, EkResults};
+use chrono::prelude::*;
+use serde_json::{json, Value};
+use std::cmp::min;
+use std::collections::HashSet;
+
+/// Columns of the headline table, with the type each is returned as
+const HEADLINE_COLUMNS: [(&str, ColumnType); 4] = [
+    ("storyId", ColumnType::Str),
+    ("versionCreated", ColumnType::DateTime),
+    ("text", ColumnType::Str),
+    ("sourceCode", ColumnType::Str),
+];
+
+pub struct News {
+    connection: Connection,
+}
+
+impl News {
+    /// Most headlines the endpoint returns for a single request
+    const PAGE_SIZE: usize = 100;
+
+    pub fn new(c: Connection) -> Self {
+        Self { connection: c }
+    }
+
+    fn assemble_payload(
+        &self,
+        query: &str,
+        date_from: Option<NaiveDateTime>,
+        date_to: Option<NaiveDateTime>,
+        repository: &[String],
+        number: usize,
+    ) -> Value {
+        let mut payload = json!({
+            "number": number.to_string(),
+            "query": query,
+            "productName": self.connection.app_key(),
+            "attributionCode": "",
+            "repository": repository.join(","),
+        });
+        if let Some(d) = date_from {
+            payload["dateFrom"] = json!(format_date(d));
+        }
+        if let Some(d) = date_to {
+            payload["dateTo"] = json!(format_date(d));
+        }
+        payload
+    }
+
+    /// Fetches up to `count` headlines matching `query`, newest first. The endpoint only returns
+    /// one page at a time, so pages are requested one after the other with `dateTo` moved to the
+    /// oldest headline seen until the range or `count` is exhausted.
+    pub fn get_headlines(
+        &self,
+        query: &str,
+        date_from: Option<NaiveDateTime>,
+        date_to: Option<NaiveDateTime>,
+        repository: &[String],
+        count: usize,
+    ) -> EkResults {
+        let direction = Direction::NewsHeadlines;
+        let mut headlines: Vec<Value> = Vec::new();
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut failed = Vec::new();
+        let mut page_to = date_to;
+
+        while headlines.len() < count {
+            let number = min(News::PAGE_SIZE, count - headlines.len());
+            let payload = self.assemble_payload(query, date_from, page_to, repository, number);
+            let res = match self
+                .connection
+                .send_request_async_handler(vec![payload], direction)
+            {
+                Ok(r) => r,
+                Err(e) => return EkResults::Err(e),
+            };
+            let Some(page) = res.ok.into_iter().next() else {
+                failed.extend(res.failed);
+                break;
+            };
+
+            let page = page["headlines"].as_array().cloned().unwrap_or_default();
+            let page_len = page.len();
+            let mut oldest: Option<NaiveDateTime> = None;
+            let mut new_rows = 0;
+            for headline in page {
+                let created = headline["versionCreated"].as_str().and_then(parse_datetime);
+                oldest = match (oldest, created) {
+                    (Some(o), Some(c)) => Some(o.min(c)),
+                    (o, c) => o.or(c),
+                };
+                let id = headline["storyId"].to_string();
+                if seen.insert(id) {
+                    headlines.push(headline);
+                    new_rows += 1;
+                }
+            }
+
+            // A short page means the range is exhausted, a page of nothing but repeats means
+            // more headlines share one timestamp than fit on a page
+            if page_len < number || new_rows == 0 {
+                break;
+            }
+            match oldest {
+                Some(o) if date_from.is_none_or(|f| o > f) => page_to = Some(o),
+                _ => break,
+            }
+        }
+        headlines.truncate(count);
+
+        if headlines.is_empty() {
+            let res = ChunkResults {
+                failed,
+                ..Default::default()
+            };
+            return EkResults::Err(res.no_data());
+        }
+
+        let names = HEADLINE_COLUMNS
+            .iter()
+            .map(|(n, _)| n.to_string())
+            .collect();
+        let columns = HEADLINE_COLUMNS
+            .iter()
+            .map(|(name, t)| {
+                let values = headlines.iter().map(|h| h[*name].clone()).collect();
+                Column::from_values(values, Some(*t))
+            })
+            .collect();
+        EkResults::Columns {
+            names,
+            columns,
+            failed,
+        }
+    }
+
+    /// Fetches the full stories for `story_ids` concurrently, as HTML and as plain text. Stories
+    /// only available as a link have the link in `url` instead
+    pub fn get_stories(&self, story_ids: Vec<String>) -> EkResults {
+        let direction = Direction::NewsStory;
+        let payloads = story_payloads(&story_ids, self.connection.app_key());
+        let res = match self
+            .connection
+            .send_request_async_handler(payloads, direction)
+        {
+            Ok(r) => r,
+            Err(e) => return EkResults::Err(e),
+        };
+        if res.ok.is_empty() {
+            return EkResults::Err(res.no_data());
+        }
+        story_columns(res)
+    }
+}
+
+/// One payload per story, a story asked for twice is only requested once
+fn story_payloads(story_ids: &[String], app_key: &str) -> Vec<Value> {
+    let mut seen = HashSet::new();
+    story_ids
+        .iter()
+        .filter(|id| seen.insert(id.as_str()))
+        .map(|id| {
+            json!({
+                "attributionCode": "",
+                "productName": app_key,
+                "storyId": id,
+            })
+        })
+        .collect()
+}
+
+/// Story table with one row per successful response, the story id is read from the payload
+/// each response was sent for
+fn story_columns(res: ChunkResults) -> EkResults {
+    let mut ids = Vec::new();
+    let mut html = Vec::new();
+    let mut text = Vec::new();
+    let mut url = Vec::new();
+    for (payload, story) in res.sent.iter().zip(&res.ok) {
+        let story_html = story["story"]["storyHtml"].as_str();
+        ids.push(payload["storyId"].clone());
+        html.push(json!(story_html));
+        text.push(json!(story_html.map(html_to_text)));
+        url.push(
+            story
+                .get("viewURL")
+                .or(story.get("webURL"))
+                .cloned()
+                .unwrap_or_default(),
+        );
+    }
+
+    let names = ["storyId", "html", "text", "url"]
+        .map(String::from)
+        .to_vec();
+    let columns = [ids, html, text, url]
+        .into_iter()
+        .map(|values| Column::from_values(values, Some(ColumnType::Str)))
+        .collect();
+    EkResults::Columns {
+        names,
+        columns,
+        failed: res.failed,
+    }
+}
+
+/// Plain-text rendering of a story: tags are dropped, block elements start a new line and
+/// entities are decoded
+fn html_to_text(html: &str) -> String {
+    const BLOCKS: [&str; 15] = [
+        "br",
+        "p",
+        "div",
+        "li",
+        "tr",
+        "table",
+        "pre",
+        "blockquote",
+        "h1",
+        "h2",
+        "h3",
+        "h4",
+        "h5",
+        "h6",
+        "hr",
+    ];
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start].replace(['\n', '\r', '\t'], " "));
+        let Some(len) = rest[start..].find('>') else {
+            rest = &rest[start..];
+            break;
+        };
+        let tag = rest[start + 1..start + len].to_ascii_lowercase();
+        rest = &rest[start + len + 1..];
+
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("");
+        if !closing && (name == "script" || name == "style") {
+            let end = rest.to_ascii_lowercase().find(&format!("</{name}"));
+            rest = end.map_or("", |i| &rest[i..]);
+        } else if BLOCKS.contains(&name) {
+            text.push('\n');
+        }
+    }
+    text.push_str(&rest.replace(['\n', '\r', '\t'], " "));
+
+    let text = decode_entities(&text);
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !line.is_empty() || lines.last().is_some_and(|l| !l.is_empty()) {
+            lines.push(line);
+        }
+    }
+    lines.join("\n").trim().to_string()
+}
+
+fn decode_entities(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 10)
+            .and_then(|end| {
+                let entity = &rest[1..end + 1];
+                let c = match entity {
+                    "amp" => Some('&'),
+                    "lt" => Some('<'),
+                    "gt" => Some('>'),
+                    "quot" => Some('"'),
+                    "apos" => Some('\''),
+                    "nbsp" => Some(' '),
+                    _ => match entity.strip_prefix("#x").or(entity.strip_prefix("#X")) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                        None => entity
+                            .strip_prefix('#')
+                            .and_then(|n| n.parse().ok())
+                            .and_then(char::from_u32),
+                    },
+                };
+                c.map(|c| (c, end + 2))
+            });
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn format_date(d: NaiveDateTime) -> String {
+    d.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{EkError, ErrorKind, FailedChunk};
+
+    #[test]
+    fn test_html_to_text() {
+        let html = "<div><p>Apple &amp; Tesla\n  rose</p><script>var x = '<p>';</script>\
+                    <p>Close&nbsp;&#8364;10&#x21;<br/>Next</p></div>";
+        assert_eq!(
+            html_to_text(html),
+            "Apple & Tesla rose\n\nClose \u{20ac}10!\nNext"
+        );
+        assert_eq!(html_to_text("a &unknown; b < c"), "a &unknown; b < c");
+    }
+
+    #[test]
+    fn test_story_columns() {
+        let ids = ["A", "B", "A", "C"].map(String::from);
+        let payloads = story_payloads(&ids, "key");
+        assert_eq!(payloads.len(), 3);
+        assert_eq!(payloads[2]["storyId"], "C");
+
+        // B failed and C came back before A
+        let mut res = ChunkResults::default();
+        let story = |html: &str| json!({"story": {"storyHtml": html}});
+        res.push_ok(payloads[2].clone(), story("<p>c</p>"));
+        res.push_ok(payloads[0].clone(), story("<p>a</p>"));
+        res.failed.push(FailedChunk::new(
+            payloads[1].clone(),
+            1,
+            EkError::new(ErrorKind::Api, "story not found"),
+        ));
+
+        let EkResults::Columns {
+            columns, failed, ..
+        } = story_columns(res)
+        else {
+            panic!("expected columns");
+        };
+        let str_column = |c: &Column| match c {
+            Column::Str(v) => v.clone(),
+            _ => panic!("expected a string column"),
+        };
+        assert_eq!(
+            str_column(&columns[0]),
+            vec![Some("C".to_string()), Some("A".to_string())]
+        );
+        assert_eq!(
+            str_column(&columns[2]),
+            vec![Some("c".to_string()), Some("a".to_string())]
+        );
+        assert_eq!(failed[0].payload["storyId"], "B");
+    }
+}
@@ -0,0 +1,70 @@
+use crate::connection::RateLimits;
+use crate::utils::Column;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Rough size of one cell of a response in bytes, used to estimate the data received
+const BYTES_PER_CELL: u64 = 24;
+
+/// One payload of a request plan
+pub struct PlannedPayload {
+    pub instruments: Vec<String>,
+    pub start: Option<String>,
+    pub end: Option<String>,
+    /// Rows expected over all instruments
+    pub rows: usize,
+}
+
+/// The payloads a request is split into, worked out without contacting the proxy
+pub struct RequestPlan {
+    pub payloads: Vec<PlannedPayload>,
+    /// Columns of every row, used to estimate the size of the responses
+    pub columns: usize,
+}
+
+impl RequestPlan {
+    pub fn rows(&self) -> usize {
+        self.payloads.iter().map(|p| p.rows).sum()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        (self.rows() * self.columns) as u64 * BYTES_PER_CELL
+    }
+
+    /// Lower bound on the time the request takes, set by the rate limits alone
+    pub fn estimated_time(&self, limits: &RateLimits) -> Duration {
+        limits.estimate(self.payloads.len(), self.bytes())
+    }
+
+    pub fn exceeds_daily_limit(&self, limits: &RateLimits) -> bool {
+        self.payloads.len() > limits.per_day as usize
+    }
+
+    /// One row per payload with its number, instruments, date range and expected rows
+    pub fn table(&self) -> (Vec<String>, Vec<Column>) {
+        let mut columns: Vec<Vec<Value>> = vec![Vec::new(); 6];
+        for (i, p) in self.payloads.iter().enumerate() {
+            columns[0].push(json!(i + 1));
+            columns[1].push(json!(p.instruments.len()));
+            columns[2].push(json!(p.instruments.join(",")));
+            columns[3].push(json!(p.start));
+            columns[4].push(json!(p.end));
+            columns[5].push(json!(p.rows));
+        }
+        let names = [
+            "payload",
+            "n_instruments",
+            "instruments",
+            "start",
+            "end",
+            "rows",
+        ]
+        .map(String::from)
+        .to_vec();
+        let columns = columns
+            .into_iter()
+            .map(|c| Column::from_values(c, None))
+            .collect();
+        (names, columns)
+    }
+}
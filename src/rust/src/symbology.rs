@@ -0,0 +1,233 @@
+use crate::connection::{Connection, Direction};
+use crate::utils::{Column, ColumnType, EkResults};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+/// Identifier types understood by SymbologySearch
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SymbolType {
+    Ric,
+    Isin,
+    Cusip,
+    Sedol,
+    Ticker,
+    LipperId,
+    Imo,
+    OaPermId,
+}
+
+impl SymbolType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "ric" => Some(SymbolType::Ric),
+            "isin" => Some(SymbolType::Isin),
+            "cusip" => Some(SymbolType::Cusip),
+            "sedol" => Some(SymbolType::Sedol),
+            "ticker" => Some(SymbolType::Ticker),
+            "lipperid" => Some(SymbolType::LipperId),
+            "imo" => Some(SymbolType::Imo),
+            "oapermid" | "permid" => Some(SymbolType::OaPermId),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            SymbolType::Ric => "RIC",
+            SymbolType::Isin => "ISIN",
+            SymbolType::Cusip => "CUSIP",
+            SymbolType::Sedol => "SEDOL",
+            SymbolType::Ticker => "ticker",
+            SymbolType::LipperId => "lipperID",
+            SymbolType::Imo => "IMO",
+            SymbolType::OaPermId => "OAPermID",
+        }
+    }
+}
+
+/// Outcome for one input symbol
+struct Mapping {
+    values: Vec<Option<String>>,
+    status: &'static str,
+    message: Option<String>,
+}
+
+pub struct Symbology {
+    connection: Connection,
+}
+
+impl Symbology {
+    /// Most symbols the endpoint accepts in one request
+    const MAX_SYMBOLS: usize = 1000;
+
+    pub fn new(c: Connection) -> Self {
+        Self { connection: c }
+    }
+
+    fn assemble_payload(symbols: &[String], from: SymbolType, to: &[SymbolType]) -> Value {
+        let to: Vec<&str> = to.iter().map(|t| t.as_str()).collect();
+        json!({
+            "symbols": symbols,
+            "from": from.as_str(),
+            "to": to,
+            "bestMatchOnly": false,
+        })
+    }
+
+    /// Converts `symbols` of type `from` into each type in `to`. Every symbol gets one row, in
+    /// input order, with the best match per type and a status of "mapped", "ambiguous" (more
+    /// than one candidate), "unmapped" or "failed" (its chunk could not be downloaded).
+    pub fn convert(
+        &self,
+        symbols: Vec<String>,
+        from: SymbolType,
+        to: Vec<SymbolType>,
+    ) -> EkResults {
+        let direction = Direction::SymbologySearch;
+        let mut seen = HashSet::new();
+        let unique: Vec<String> = symbols
+            .into_iter()
+            .filter(|s| seen.insert(s.clone()))
+            .collect();
+        let payloads: Vec<Value> = unique
+            .chunks(Symbology::MAX_SYMBOLS)
+            .map(|chunk| Symbology::assemble_payload(chunk, from, &to))
+            .collect();
+
+        let res = match self
+            .connection
+            .send_request_async_handler(payloads, direction)
+        {
+            Ok(r) => r,
+            Err(e) => return EkResults::Err(e),
+        };
+        if res.ok.is_empty() {
+            return EkResults::Err(res.no_data());
+        }
+
+        let mut mappings: HashMap<String, Mapping> = HashMap::new();
+        for response in &res.ok {
+            for mapped in response["mappedSymbols"].as_array().into_iter().flatten() {
+                if let Some(symbol) = mapped["symbol"].as_str() {
+                    mappings.insert(symbol.to_string(), mapping(mapped, &to));
+                }
+            }
+        }
+        for f in &res.failed {
+            for symbol in &f.error.instruments {
+                mappings.insert(
+                    symbol.clone(),
+                    Mapping {
+                        values: vec![None; to.len()],
+                        status: "failed",
+                        message: Some(f.error.to_string()),
+                    },
+                );
+            }
+        }
+
+        let mut names = vec!["symbol".to_string()];
+        names.extend(to.iter().map(|t| t.as_str().to_string()));
+        names.extend(["status".to_string(), "message".to_string()]);
+
+        let mut columns: Vec<Vec<Value>> = vec![Vec::with_capacity(unique.len()); names.len()];
+        for symbol in unique {
+            let m = mappings.remove(&symbol).unwrap_or(Mapping {
+                values: vec![None; to.len()],
+                status: "unmapped",
+                message: Some("Symbol missing from the response".to_string()),
+            });
+            columns[0].push(json!(symbol));
+            for (i, v) in m.values.into_iter().enumerate() {
+                columns[i + 1].push(json!(v));
+            }
+            columns[to.len() + 1].push(json!(m.status));
+            columns[to.len() + 2].push(json!(m.message));
+        }
+
+        EkResults::Columns {
+            names,
+            columns: columns
+                .into_iter()
+                .map(|c| Column::from_values(c, Some(ColumnType::Str)))
+                .collect(),
+            failed: res.failed,
+        }
+    }
+}
+
+/// Best value per target type of one entry of `mappedSymbols`. The endpoint lists candidates
+/// under the plural of the type, e.g. `RICs`, and may name a `bestMatch`
+fn mapping(mapped: &Value, to: &[SymbolType]) -> Mapping {
+    let mut values = Vec::with_capacity(to.len());
+    let mut ambiguous = Vec::new();
+    for t in to {
+        let mut candidates: Vec<String> = Vec::new();
+        for c in mapped[format!("{}s", t.as_str())]
+            .as_array()
+            .into_iter()
+            .flatten()
+        {
+            if let Some(c) = c.as_str().filter(|c| !candidates.iter().any(|x| x == c)) {
+                candidates.push(c.to_string());
+            }
+        }
+        let best = mapped["bestMatch"][t.as_str()].as_str().map(String::from);
+        if best.is_none() && candidates.len() > 1 {
+            ambiguous.push(format!(
+                "{} candidates: {}",
+                t.as_str(),
+                candidates.join(", ")
+            ));
+        }
+        values.push(best.or_else(|| candidates.into_iter().next()));
+    }
+
+    let error = mapped["error"]
+        .as_str()
+        .or_else(|| mapped["bestMatch"]["error"].as_str())
+        .map(String::from);
+    let (status, message) = if values.iter().all(|v| v.is_none()) {
+        let msg = error.unwrap_or_else(|| "No match found".to_string());
+        ("unmapped", Some(msg))
+    } else if !ambiguous.is_empty() {
+        ("ambiguous", Some(ambiguous.join("; ")))
+    } else {
+        ("mapped", None)
+    };
+    Mapping {
+        values,
+        status,
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mapping() {
+        let to = [SymbolType::Ric, SymbolType::Isin];
+        let mapped = json!({
+            "symbol": "US5949181045",
+            "RICs": ["MSFT.O", "MSFT.OQ", "MSFT.O"],
+            "ISINs": ["US5949181045"]
+        });
+        let m = mapping(&mapped, &to);
+        assert_eq!(
+            m.values,
+            vec![Some("MSFT.O".to_string()), Some("US5949181045".to_string())]
+        );
+        assert_eq!(m.status, "ambiguous");
+        assert_eq!(m.message.unwrap(), "RIC candidates: MSFT.O, MSFT.OQ");
+
+        let mapped = json!({"symbol": "US5949181045", "bestMatch": {"RIC": "MSFT.O"}, "RICs": ["MSFT.O", "MSFT.OQ"]});
+        assert_eq!(mapping(&mapped, &to[..1]).status, "mapped");
+
+        let mapped = json!({"symbol": "XX", "error": "No best match available"});
+        let m = mapping(&mapped, &to);
+        assert_eq!(m.status, "unmapped");
+        assert_eq!(m.message.unwrap(), "No best match available");
+    }
+}
@@ -1,7 +1,11 @@
 use crate::connection::{Connection, Direction};
-use crate::utils::{clean_string, EkError, EkResults};
+use crate::plan::{PlannedPayload, RequestPlan};
+use crate::utils::{
+    clean_string, parse_datetime, Column, ColumnType, EkError, EkResults, ErrorKind, Table,
+};
 use chrono::prelude::*;
 use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 pub enum Interval {
     Minute,
@@ -25,6 +29,27 @@ impl Interval {
             Interval::Yearly => "yearly",
         }
     }
+
+    /// Smallest step chunk boundaries are placed on, bars coarser than a day start on whole days
+    fn unit(&self) -> chrono::Duration {
+        match self {
+            Interval::Minute => chrono::Duration::minutes(1),
+            Interval::Hour => chrono::Duration::hours(1),
+            _ => chrono::Duration::days(1),
+        }
+    }
+
+    /// Rounds a timestamp down to the start of its unit
+    fn align(&self, t: NaiveDateTime) -> NaiveDateTime {
+        let day = t.date().and_time(NaiveTime::MIN);
+        match self {
+            Interval::Minute => {
+                day + chrono::Duration::minutes(t.hour() as i64 * 60 + t.minute() as i64)
+            }
+            Interval::Hour => day + chrono::Duration::hours(t.hour() as i64),
+            _ => day,
+        }
+    }
 }
 
 pub struct TimeSeries {
@@ -44,21 +69,52 @@ impl TimeSeries {
         s_date: NaiveDateTime,
         e_date: NaiveDateTime,
     ) -> EkResults {
+        let payloads = build_payloads(rics, fields, s_date, e_date, &frq);
+        self.fetch(payloads, &HashMap::new())
+    }
+
+    /// Downloads only the bars after `last`, the latest timestamp already held for each RIC.
+    /// RICs without one are requested from `s_date`, RICs that are up to date are skipped
+    pub fn update(
+        &self,
+        rics: Vec<String>,
+        fields: Vec<String>,
+        frq: Interval,
+        last: HashMap<String, NaiveDateTime>,
+        s_date: NaiveDateTime,
+        e_date: NaiveDateTime,
+    ) -> EkResults {
+        let payloads: Vec<Value> = missing_ranges(rics, &last, s_date, e_date, &frq)
+            .into_iter()
+            .flat_map(|(start, group)| build_payloads(group, fields.clone(), start, e_date, &frq))
+            .collect();
+        if payloads.is_empty() {
+            return EkResults::Err(EkError::new(ErrorKind::NoData, "Every RIC is up to date"));
+        }
+        self.fetch(payloads, &last)
+    }
+
+    /// Sends `payloads` and merges the responses, dropping rows at or before `last` of their RIC
+    fn fetch(&self, payloads: Vec<Value>, last: &HashMap<String, NaiveDateTime>) -> EkResults {
         let direction = Direction::TimeSeries;
-        let payloads = build_payloads(rics, fields, s_date, e_date, frq);
-        let res = match self.connection.send_request_async_handler(payloads, direction) {
+        let res = match self
+            .connection
+            .send_request_async_handler(payloads, direction)
+        {
             Ok(r) => r,
             Err(e) => return EkResults::Err(e),
         };
-        if res.is_empty() {
-            return EkResults::Err(EkError::NoData("No data returned from Refinitiv".to_string()));
+        if res.ok.is_empty() {
+            return EkResults::Err(res.no_data());
         }
 
         // Collect all response chunks into columnar data
         let mut all_names: Vec<String> = Vec::new();
-        let mut all_columns: Vec<Vec<Option<String>>> = Vec::new();
+        let mut all_columns: Vec<Vec<Value>> = Vec::new();
+        let mut types: HashMap<String, ColumnType> = HashMap::new();
 
-        for response in res {
+        for response in res.ok {
+            types.extend(field_types(&response));
             match to_columns(response) {
                 Err(e) => return EkResults::Err(e),
                 Ok(None) => {}
@@ -78,12 +134,43 @@ impl TimeSeries {
         }
 
         if all_names.is_empty() {
-            return EkResults::Err(EkError::NoData("No valid timeseries data".to_string()));
+            return EkResults::Err(EkError::new(ErrorKind::NoData, "No valid timeseries data"));
         }
 
+        let columns = drop_known_rows(&all_names, dedup_rows(&all_names, all_columns), last)
+            .into_iter()
+            .zip(&all_names)
+            .map(|(col, name)| Column::from_values(col, types.get(name).copied()))
+            .collect();
         EkResults::Columns {
             names: all_names,
-            columns: all_columns,
+            columns,
+            failed: res.failed,
+        }
+    }
+
+    /// The payloads `get_timeseries` would send for this request, without sending them
+    pub fn plan(
+        rics: &[String],
+        fields: &[String],
+        frq: Interval,
+        s_date: NaiveDateTime,
+        e_date: NaiveDateTime,
+    ) -> RequestPlan {
+        let payloads = chunks(rics, s_date, e_date, &frq)
+            .into_iter()
+            .map(|(group, sd, ed)| PlannedPayload {
+                instruments: group.to_vec(),
+                start: Some(sd.to_string()),
+                end: Some(ed.to_string()),
+                // Chunks are inclusive, so the last unit counts as well
+                rows: group.len() * rows_per_ric(&frq, sd, ed + frq.unit()).max(1),
+            })
+            .collect();
+        RequestPlan {
+            payloads,
+            // Every field, TIMESTAMP included, plus the RIC
+            columns: fields.len().max(1) + 1,
         }
     }
 }
@@ -92,8 +179,8 @@ impl TimeSeries {
 fn align_columns(
     target_names: &[String],
     source_names: &[String],
-    source_columns: Vec<Vec<Option<String>>>,
-) -> Vec<Vec<Option<String>>> {
+    source_columns: Vec<Vec<Value>>,
+) -> Vec<Vec<Value>> {
     let n_rows = source_columns.first().map(|c| c.len()).unwrap_or(0);
     target_names
         .iter()
@@ -101,24 +188,114 @@ fn align_columns(
             if let Some(idx) = source_names.iter().position(|n| n == name) {
                 source_columns[idx].clone()
             } else {
-                vec![None; n_rows]
+                vec![Value::Null; n_rows]
             }
         })
         .collect()
 }
 
+/// Drops rows whose (RIC, TIMESTAMP) pair was already seen, keeping the first occurrence
+fn dedup_rows(names: &[String], columns: Vec<Vec<Value>>) -> Vec<Vec<Value>> {
+    let (Some(ric), Some(ts)) = (
+        names.iter().position(|n| n == "RIC"),
+        names.iter().position(|n| n == "TIMESTAMP"),
+    ) else {
+        return columns;
+    };
+    let n_rows = columns.first().map(|c| c.len()).unwrap_or(0);
+    let mut seen = HashSet::with_capacity(n_rows);
+    let keep: Vec<bool> = (0..n_rows)
+        .map(|row| seen.insert((columns[ric][row].to_string(), columns[ts][row].to_string())))
+        .collect();
+    retain_rows(columns, &keep)
+}
+
+/// Drops rows whose TIMESTAMP is at or before the one in `last` for their RIC
+fn drop_known_rows(
+    names: &[String],
+    columns: Vec<Vec<Value>>,
+    last: &HashMap<String, NaiveDateTime>,
+) -> Vec<Vec<Value>> {
+    let (Some(ric), Some(ts)) = (
+        names.iter().position(|n| n == "RIC"),
+        names.iter().position(|n| n == "TIMESTAMP"),
+    ) else {
+        return columns;
+    };
+    if last.is_empty() {
+        return columns;
+    }
+    let n_rows = columns.first().map(|c| c.len()).unwrap_or(0);
+    let keep: Vec<bool> = (0..n_rows)
+        .map(|row| {
+            let known = columns[ric][row].as_str().and_then(|r| last.get(r));
+            let t = columns[ts][row].as_str().and_then(parse_datetime);
+            match (known, t) {
+                (Some(known), Some(t)) => t > *known,
+                _ => true,
+            }
+        })
+        .collect();
+    retain_rows(columns, &keep)
+}
+
+fn retain_rows(columns: Vec<Vec<Value>>, keep: &[bool]) -> Vec<Vec<Value>> {
+    if keep.iter().all(|k| *k) {
+        return columns;
+    }
+    columns
+        .into_iter()
+        .map(|col| {
+            col.into_iter()
+                .zip(keep)
+                .filter_map(|(v, k)| k.then_some(v))
+                .collect()
+        })
+        .collect()
+}
+
 fn build_payloads(
     rics: Vec<String>,
     fields: Vec<String>,
     s_date: NaiveDateTime,
     e_date: NaiveDateTime,
-    frq: Interval,
+    frq: &Interval,
 ) -> Vec<Value> {
+    chunks(&rics, s_date, e_date, frq)
+        .into_iter()
+        .map(|(ric_group, sd, ed)| {
+            assemble_payload(ric_group.to_vec(), &fields, frq.as_str(), &sd, &ed)
+        })
+        .collect()
+}
+
+/// RICs grouped by the start of the range they are missing, one unit of `frq` after their last
+/// bar or `s_date` for RICs without one. RICs with nothing missing up to `e_date` are left out
+fn missing_ranges(
+    rics: Vec<String>,
+    last: &HashMap<String, NaiveDateTime>,
+    s_date: NaiveDateTime,
+    e_date: NaiveDateTime,
+    frq: &Interval,
+) -> BTreeMap<NaiveDateTime, Vec<String>> {
+    let mut groups: BTreeMap<NaiveDateTime, Vec<String>> = BTreeMap::new();
+    for ric in rics {
+        let start = match last.get(&ric) {
+            Some(t) => frq.align(*t) + frq.unit(),
+            None => s_date,
+        };
+        if start <= e_date {
+            groups.entry(start).or_default().push(ric);
+        }
+    }
+    groups
+}
+
+/// Rows a single RIC returns between s_date and e_date
+fn rows_per_ric(frq: &Interval, s_date: NaiveDateTime, e_date: NaiveDateTime) -> usize {
     let trading_days: usize = 252;
-    let max_rows: usize = 3000;
-    let max_companies: usize = 300;
     let period = e_date.signed_duration_since(s_date);
-    let rows_pr = match frq {
+    match frq {
         Interval::Minute => (period.num_minutes() as f32 / 2f32).ceil() as usize,
         Interval::Hour => (period.num_hours() as f32 / 2f32).ceil() as usize,
         Interval::Daily => {
@@ -128,7 +305,19 @@ fn build_payloads(
         Interval::Monthly => ((period.num_days() as f32 / 365f32) * 12f32).ceil() as usize,
         Interval::Quarterly => ((period.num_days() as f32 / 365f32) * 4f32).ceil() as usize,
         Interval::Yearly => (period.num_days() as f32 / 365f32).ceil() as usize,
-    };
+    }
+}
+
+/// Groups of RICs and the date ranges each group is requested over
+fn chunks<'a>(
+    rics: &'a [String],
+    s_date: NaiveDateTime,
+    e_date: NaiveDateTime,
+    frq: &Interval,
+) -> Vec<(&'a [String], NaiveDateTime, NaiveDateTime)> {
+    let max_rows: usize = 3000;
+    let max_companies: usize = 300;
+    let rows_pr = rows_per_ric(frq, s_date, e_date);
 
     let ric_group_size = if rics.len() > max_companies {
         max_companies
@@ -136,23 +325,16 @@ fn build_payloads(
         rics.len()
     };
 
-    let time_groups =
-        ((rows_pr as f32 * ric_group_size as f32) / max_rows as f32).ceil() as usize;
-    let time_groups = create_intervals(time_groups.max(1), s_date, e_date);
+    let time_groups = ((rows_pr as f32 * ric_group_size as f32) / max_rows as f32).ceil() as usize;
+    let time_groups = create_intervals(time_groups.max(1), s_date, e_date, frq);
 
-    let mut payloads: Vec<Value> = Vec::new();
+    let mut chunks = Vec::new();
     for ric_group in rics.chunks(ric_group_size) {
         for (sd, ed) in time_groups.iter() {
-            payloads.push(assemble_payload(
-                ric_group.to_vec(),
-                &fields,
-                frq.as_str(),
-                sd,
-                ed,
-            ));
+            chunks.push((ric_group, *sd, *ed));
         }
     }
-    payloads
+    chunks
 }
 
 fn assemble_payload(
@@ -171,22 +353,43 @@ fn assemble_payload(
     })
 }
 
+/// Splits [s_date, e_date] into at most `groups` inclusive intervals. The first starts at
+/// `s_date` and the last ends at `e_date`, the boundaries between them fall on whole units of
+/// `frq` and each interval starts one unit after the previous one ends so no bar is requested
+/// twice
 fn create_intervals(
     groups: usize,
     s_date: NaiveDateTime,
     e_date: NaiveDateTime,
+    frq: &Interval,
 ) -> Vec<(NaiveDateTime, NaiveDateTime)> {
-    let mut intervals: Vec<(NaiveDateTime, NaiveDateTime)> = Vec::with_capacity(groups);
-    let dur = e_date.signed_duration_since(s_date) / groups as i32;
-    let mut start = s_date;
-    for _ in 0..groups {
-        let end = if start + dur > e_date {
-            e_date
-        } else {
-            start + dur
-        };
-        intervals.push((start, end));
-        start = end;
+    let unit = frq.unit();
+    let start = frq.align(s_date);
+    let end = frq.align(e_date);
+    if end <= start {
+        return vec![(s_date, e_date)];
+    }
+
+    let units = (end - start).num_seconds() / unit.num_seconds() + 1;
+    let groups = (groups as i64).clamp(1, units);
+    let per_group = (units + groups - 1) / groups;
+
+    let mut intervals: Vec<(NaiveDateTime, NaiveDateTime)> = Vec::with_capacity(groups as usize);
+    let mut from = start;
+    while from <= end {
+        let to = (from + unit * (per_group - 1) as i32).min(end);
+        intervals.push((from, to));
+        from = to + unit;
+    }
+
+    // A first interval ending before s_date only holds the partial unit s_date falls in, which
+    // has no bar of its own, so the next interval takes over from s_date
+    if intervals.len() > 1 && intervals[0].1 < s_date {
+        intervals.remove(0);
+    }
+    intervals[0].0 = s_date;
+    if let Some(last) = intervals.last_mut() {
+        last.1 = e_date;
     }
     intervals
 }
@@ -204,13 +407,30 @@ fn fetch_headers(json_like: &Value) -> Option<Vec<String>> {
     Some(names)
 }
 
-fn to_columns(
-    json_like: Value,
-) -> Result<Option<(Vec<String>, Vec<Vec<Option<String>>>)>, EkError> {
+/// Column types reported in the `fields` metadata of a response
+fn field_types(json_like: &Value) -> HashMap<String, ColumnType> {
+    let mut types = HashMap::new();
+    let Some(ts_data) = json_like["timeseriesData"].as_array() else {
+        return types;
+    };
+    for fields in ts_data.iter().filter_map(|r| r["fields"].as_array()) {
+        for field in fields {
+            if let (Some(name), Some(t)) = (field["name"].as_str(), field["type"].as_str()) {
+                if let Some(t) = ColumnType::from_api(t) {
+                    types.insert(name.to_string(), t);
+                }
+            }
+        }
+    }
+    types
+}
+
+fn to_columns(json_like: Value) -> Result<Option<Table>, EkError> {
     let ts_data = match json_like["timeseriesData"].as_array() {
         None => {
-            return Err(EkError::Error(
-                "Could not parse timeseriesData as array".to_string(),
+            return Err(EkError::new(
+                ErrorKind::Error,
+                "Could not parse timeseriesData as array",
             ))
         }
         Some(r) => r,
@@ -230,7 +450,7 @@ fn to_columns(
     }
 
     // Build columns
-    let mut columns: Vec<Vec<Option<String>>> = vec![Vec::new(); headers.len()];
+    let mut columns: Vec<Vec<Value>> = vec![Vec::new(); headers.len()];
 
     for ric in ts_data {
         if ric["statusCode"] != "Normal" {
@@ -245,14 +465,9 @@ fn to_columns(
         for row in data_points {
             for (i, col) in columns.iter_mut().enumerate() {
                 if headers[i] == "RIC" {
-                    col.push(Some(ric_name.clone()));
+                    col.push(Value::String(ric_name.clone()));
                 } else {
-                    let val = &row[i];
-                    if val.is_null() {
-                        col.push(None);
-                    } else {
-                        col.push(Some(clean_string(val.to_string())));
-                    }
+                    col.push(row[i].clone());
                 }
             }
         }
@@ -260,3 +475,129 @@ fn to_columns(
 
     Ok(Some((headers, columns)))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dt(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%F %T").unwrap()
+    }
+
+    #[test]
+    fn test_create_intervals() {
+        let s = dt("2020-01-01 00:00:00");
+        let e = dt("2020-01-10 00:00:00");
+        let intervals = create_intervals(3, s, e, &Interval::Daily);
+        assert_eq!(
+            intervals,
+            vec![
+                (dt("2020-01-01 00:00:00"), dt("2020-01-04 00:00:00")),
+                (dt("2020-01-05 00:00:00"), dt("2020-01-08 00:00:00")),
+                (dt("2020-01-09 00:00:00"), dt("2020-01-10 00:00:00")),
+            ]
+        );
+
+        let s = dt("2020-01-01 09:30:45");
+        let e = dt("2020-01-01 10:29:10");
+        let intervals = create_intervals(4, s, e, &Interval::Minute);
+        assert_eq!(intervals.len(), 4);
+        assert_eq!(intervals[0].0, s);
+        assert_eq!(intervals[3].1, e);
+        for pair in intervals.windows(2) {
+            assert_eq!(pair[1].0, pair[0].1 + chrono::Duration::minutes(1));
+        }
+
+        assert_eq!(create_intervals(10, s, s, &Interval::Daily), vec![(s, s)]);
+        let s = dt("2020-01-01 00:00:00");
+        let e = dt("2020-01-02 00:00:00");
+        assert_eq!(create_intervals(10, s, e, &Interval::Daily).len(), 2);
+
+        // Shorter than one unit, within a day and across midnight
+        let s = dt("2020-01-01 09:00:00");
+        let e = dt("2020-01-01 17:00:00");
+        assert_eq!(create_intervals(10, s, e, &Interval::Daily), vec![(s, e)]);
+        let s = dt("2020-01-01 20:00:00");
+        let e = dt("2020-01-02 04:00:00");
+        assert_eq!(create_intervals(10, s, e, &Interval::Daily), vec![(s, e)]);
+        let e = dt("2020-01-01 20:00:59");
+        assert_eq!(create_intervals(10, s, e, &Interval::Minute), vec![(s, e)]);
+    }
+
+    #[test]
+    fn test_dedup_rows() {
+        let names = vec![
+            "TIMESTAMP".to_string(),
+            "CLOSE".to_string(),
+            "RIC".to_string(),
+        ];
+        let col = |v: &[&str]| v.iter().map(|s| json!(s)).collect::<Vec<_>>();
+        let columns = vec![
+            col(&["2020-01-01", "2020-01-02", "2020-01-02", "2020-01-02"]),
+            col(&["1", "2", "2", "3"]),
+            col(&["A", "A", "A", "B"]),
+        ];
+        let res = dedup_rows(&names, columns);
+        assert_eq!(res[0], col(&["2020-01-01", "2020-01-02", "2020-01-02"]));
+        assert_eq!(res[2], col(&["A", "A", "B"]));
+    }
+
+    #[test]
+    fn test_missing_ranges() {
+        let rics: Vec<String> = ["A", "B", "C", "D"].map(String::from).to_vec();
+        let last = HashMap::from([
+            ("A".to_string(), dt("2024-05-30 00:00:00")),
+            ("B".to_string(), dt("2024-05-30 00:00:00")),
+            ("C".to_string(), dt("2024-06-03 00:00:00")),
+        ]);
+        let s = dt("2020-01-01 00:00:00");
+        let e = dt("2024-06-03 00:00:00");
+        let groups = missing_ranges(rics, &last, s, e, &Interval::Daily);
+        assert_eq!(
+            groups.into_iter().collect::<Vec<_>>(),
+            vec![
+                (s, vec!["D".to_string()]),
+                (
+                    dt("2024-05-31 00:00:00"),
+                    vec!["A".to_string(), "B".to_string()]
+                ),
+            ]
+        );
+
+        let names = vec!["TIMESTAMP".to_string(), "RIC".to_string()];
+        let col = |v: &[&str]| v.iter().map(|s| json!(s)).collect::<Vec<_>>();
+        let columns = vec![
+            col(&[
+                "2024-05-30T00:00:00Z",
+                "2024-05-31T00:00:00Z",
+                "2024-05-30T00:00:00Z",
+            ]),
+            col(&["A", "A", "D"]),
+        ];
+        let res = drop_known_rows(&names, columns, &last);
+        assert_eq!(res[1], col(&["A", "D"]));
+    }
+
+    #[test]
+    fn test_plan() {
+        let rics: Vec<String> = (0..600).map(|i| format!("R{i}")).collect();
+        let fields = vec!["TIMESTAMP".to_string(), "CLOSE".to_string()];
+        let s = dt("2020-01-01 00:00:00");
+        let e = dt("2020-12-31 00:00:00");
+        let plan = TimeSeries::plan(&rics, &fields, Interval::Daily, s, e);
+
+        let payloads = build_payloads(rics, fields, s, e, &Interval::Daily);
+        assert_eq!(plan.payloads.len(), payloads.len());
+        assert!(plan.payloads.iter().all(|p| p.instruments.len() == 300));
+        assert_eq!(
+            plan.payloads[0].start.as_deref(),
+            Some("2020-01-01 00:00:00")
+        );
+        assert!(plan.rows() >= 600 * 251);
+
+        let limits = crate::connection::RateLimits::default();
+        let expected = (payloads.len() - 5) as f64 / 5.0;
+        assert!((plan.estimated_time(&limits).as_secs_f64() - expected).abs() < 1e-6);
+        assert!(!plan.exceeds_daily_limit(&limits));
+    }
+}
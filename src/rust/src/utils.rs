@@ -1,47 +1,406 @@
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::fmt;
-use serde_json::{json, Value};
 
 pub fn clean_string(s: String) -> String {
     s.replace('"', "")
 }
 
+/// Column names with the raw JSON cells of every column, typed once all chunks are merged
+pub type Table = (Vec<String>, Vec<Vec<Value>>);
+
+/// Type of an output column, either reported by the API or inferred from the values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColumnType {
+    Float,
+    Int,
+    Bool,
+    Date,
+    DateTime,
+    Str,
+}
+
+impl ColumnType {
+    /// Type named in the field metadata of a response, e.g. `{"name": "CLOSE", "type": "Double"}`
+    pub fn from_api(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "double" | "float" | "decimal" => Some(ColumnType::Float),
+            "long" | "int" | "integer" => Some(ColumnType::Int),
+            "bool" | "boolean" => Some(ColumnType::Bool),
+            "date" => Some(ColumnType::Date),
+            "datetime" => Some(ColumnType::DateTime),
+            "string" | "text" => Some(ColumnType::Str),
+            _ => None,
+        }
+    }
+
+    /// Name of the type in the metadata returned to R, the inverse of `from_api`
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColumnType::Float => "double",
+            ColumnType::Int => "long",
+            ColumnType::Bool => "bool",
+            ColumnType::Date => "date",
+            ColumnType::DateTime => "datetime",
+            ColumnType::Str => "string",
+        }
+    }
+}
+
+/// A typed output column, `None` is a missing value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Column {
+    Float(Vec<Option<f64>>),
+    Int(Vec<Option<i64>>),
+    Bool(Vec<Option<bool>>),
+    Date(Vec<Option<NaiveDate>>),
+    /// Timestamps in UTC
+    DateTime(Vec<Option<NaiveDateTime>>),
+    Str(Vec<Option<String>>),
+}
+
+impl Column {
+    /// Types the raw cells with `hint` when there is one, otherwise with the narrowest type every
+    /// value fits. Nulls are missing values. Eikon leaves empty cells of any type as empty
+    /// strings, so these do not decide the type and are missing unless the column is a string
+    /// column, as are values that do not convert to the hinted type. An integer hint holds whole
+    /// floats such as `1.0` too, a fraction turns the column into a float column
+    pub fn from_values(values: Vec<Value>, hint: Option<ColumnType>) -> Self {
+        let present = || values.iter().filter(|v| !is_blank(v));
+        let fits = |t: ColumnType| present().all(|v| cell_fits(v, t));
+        let column_type = match hint {
+            Some(ColumnType::Int) if present().any(|v| v.is_number() && as_whole(v).is_none()) => {
+                ColumnType::Float
+            }
+            Some(t) => t,
+            None if present().next().is_none() => ColumnType::Str,
+            None => [
+                ColumnType::Bool,
+                ColumnType::Int,
+                ColumnType::Float,
+                ColumnType::Date,
+                ColumnType::DateTime,
+            ]
+            .into_iter()
+            .find(|t| fits(*t))
+            .unwrap_or(ColumnType::Str),
+        };
+
+        let cells = values
+            .into_iter()
+            .map(|v| if v.is_null() { None } else { Some(v) });
+        match column_type {
+            ColumnType::Float => Column::Float(cells.map(|v| v.and_then(|v| v.as_f64())).collect()),
+            ColumnType::Int => Column::Int(cells.map(|v| v.and_then(|v| as_whole(&v))).collect()),
+            ColumnType::Bool => Column::Bool(cells.map(|v| v.and_then(|v| v.as_bool())).collect()),
+            ColumnType::Date => Column::Date(
+                cells
+                    .map(|v| v.and_then(|v| v.as_str().and_then(parse_date)))
+                    .collect(),
+            ),
+            ColumnType::DateTime => Column::DateTime(
+                cells
+                    .map(|v| v.and_then(|v| v.as_str().and_then(parse_datetime)))
+                    .collect(),
+            ),
+            ColumnType::Str => Column::Str(
+                cells
+                    .map(|v| {
+                        v.map(|v| match v {
+                            Value::String(s) => s,
+                            other => clean_string(other.to_string()),
+                        })
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn column_type(&self) -> ColumnType {
+        match self {
+            Column::Float(_) => ColumnType::Float,
+            Column::Int(_) => ColumnType::Int,
+            Column::Bool(_) => ColumnType::Bool,
+            Column::Date(_) => ColumnType::Date,
+            Column::DateTime(_) => ColumnType::DateTime,
+            Column::Str(_) => ColumnType::Str,
+        }
+    }
+}
+
+/// Null or an empty string, cells that fit a column of any type
+fn is_blank(v: &Value) -> bool {
+    match v {
+        Value::Null => true,
+        Value::String(s) => s.is_empty(),
+        _ => false,
+    }
+}
+
+/// Integer value of a number without a fractional part that fits an i64
+fn as_whole(v: &Value) -> Option<i64> {
+    v.as_i64().or_else(|| {
+        v.as_f64()
+            .filter(|f| f.fract() == 0.0 && *f >= i64::MIN as f64 && *f < i64::MAX as f64)
+            .map(|f| f as i64)
+    })
+}
+
+fn cell_fits(v: &Value, t: ColumnType) -> bool {
+    match t {
+        ColumnType::Float => v.is_number(),
+        ColumnType::Int => v.is_i64(),
+        ColumnType::Bool => v.is_boolean(),
+        ColumnType::Date => v.as_str().and_then(parse_date).is_some(),
+        ColumnType::DateTime => v.as_str().and_then(parse_datetime).is_some(),
+        ColumnType::Str => true,
+    }
+}
+
+fn parse_date(s: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()
+}
+
+/// Parses the timestamp formats returned by the API, e.g. `2020-01-02T00:00:00Z`
+pub fn parse_datetime(s: &str) -> Option<NaiveDateTime> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return Some(dt.naive_utc());
+    }
+    ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"]
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+}
+
+/// A payload that could not be downloaded, kept so the caller can inspect or resend it
+#[derive(Debug, Clone)]
+pub struct FailedChunk {
+    pub payload: Value,
+    pub attempts: u32,
+    pub error: EkError,
+}
+
+impl FailedChunk {
+    pub fn new(payload: Value, attempts: u32, error: EkError) -> Self {
+        let error = error.with_payload(&payload);
+        Self {
+            payload,
+            attempts,
+            error,
+        }
+    }
+}
+
+/// Responses from every chunk that succeeded together with the chunks that did not
+#[derive(Default)]
+pub struct ChunkResults {
+    pub ok: Vec<Value>,
+    /// Payload of each response in `ok`, at the same position
+    pub sent: Vec<Value>,
+    pub failed: Vec<FailedChunk>,
+}
+
+impl ChunkResults {
+    pub fn push_ok(&mut self, payload: Value, response: Value) {
+        self.sent.push(payload);
+        self.ok.push(response);
+    }
+
+    /// Error to report when no chunk returned anything usable, when chunks failed the first
+    /// failure is reported so its kind reaches R
+    pub fn no_data(&self) -> EkError {
+        match self.failed.first() {
+            None => EkError::new(ErrorKind::NoData, "No data returned from Refinitiv"),
+            Some(f) => {
+                let mut e = f.error.clone();
+                if self.failed.len() > 1 {
+                    e.message = format!(
+                        "all {} chunks failed, first error: {}",
+                        self.failed.len(),
+                        e.message
+                    );
+                }
+                e
+            }
+        }
+    }
+}
+
 pub enum EkResults {
     Columns {
         names: Vec<String>,
-        columns: Vec<Vec<Option<String>>>,
+        columns: Vec<Column>,
+        failed: Vec<FailedChunk>,
+    },
+    Raw {
+        data: Vec<Value>,
+        failed: Vec<FailedChunk>,
     },
-    Raw(Vec<Value>),
     Err(EkError),
 }
 
-#[derive(Debug)]
-pub enum EkError {
-    NoData(String),
-    NoHeaders(String),
-    AuthError(String),
-    ConnectionError(String),
-    ThreadError(String),
-    DateError(String),
-    Error(String),
+/// Category of an `EkError`, each maps to an R condition class
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    NoData,
+    NoHeaders,
+    Auth,
+    Connection,
+    Thread,
+    Date,
+    TicketExpired,
+    TicketTimeout,
+    RateLimit,
+    Api,
+    InvalidField,
+    Cancelled,
+    Error,
+}
+
+impl ErrorKind {
+    pub fn condition_class(&self) -> &'static str {
+        match self {
+            ErrorKind::NoData => "ek_no_data",
+            ErrorKind::NoHeaders => "ek_no_headers",
+            ErrorKind::Auth => "ek_auth_error",
+            ErrorKind::Connection => "ek_connection_error",
+            ErrorKind::Thread => "ek_thread_error",
+            ErrorKind::Date => "ek_date_error",
+            ErrorKind::TicketExpired => "ek_ticket_expired",
+            ErrorKind::TicketTimeout => "ek_ticket_timeout",
+            ErrorKind::RateLimit => "ek_rate_limit",
+            ErrorKind::Api => "ek_api_error",
+            ErrorKind::InvalidField => "ek_invalid_field",
+            ErrorKind::Cancelled => "ek_cancelled",
+            ErrorKind::Error => "ek_error",
+        }
+    }
+
+    fn retryable(&self) -> bool {
+        matches!(
+            self,
+            ErrorKind::Connection | ErrorKind::TicketTimeout | ErrorKind::RateLimit
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EkError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub error_code: Option<i64>,
+    pub status: Option<u16>,
+    pub instruments: Vec<String>,
+    pub fields: Vec<String>,
+    pub retryable: bool,
+}
+
+impl EkError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            error_code: None,
+            status: None,
+            instruments: Vec::new(),
+            fields: Vec::new(),
+            retryable: kind.retryable(),
+        }
+    }
+
+    /// Error for a non-success HTTP status from the proxy, whether it is sent again is up to
+    /// the retry policy
+    pub fn from_status(status: u16) -> Self {
+        let kind = match status {
+            401 | 403 => ErrorKind::Auth,
+            429 => ErrorKind::RateLimit,
+            _ => ErrorKind::Connection,
+        };
+        let mut e = EkError::new(kind, format!("HTTP status {}", status));
+        e.status = Some(status);
+        e
+    }
+
+    /// Error for an Eikon `ErrorCode`/`ErrorMessage` response, whether it is sent again is up
+    /// to the retry policy
+    pub fn from_eikon(error_code: i64, message: &Value) -> Self {
+        let kind = match error_code {
+            401 | 403 => ErrorKind::Auth,
+            429 => ErrorKind::RateLimit,
+            _ => ErrorKind::Api,
+        };
+        let mut e = EkError::new(
+            kind,
+            format!("{}: {}", error_code, clean_string(message.to_string())),
+        );
+        e.error_code = Some(error_code);
+        e
+    }
+
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Records the instruments and fields of the request that caused the error
+    pub fn with_payload(mut self, payload: &Value) -> Self {
+        let request = payload
+            .get("requests")
+            .and_then(|r| r.get(0))
+            .unwrap_or(payload);
+        let instruments = request
+            .get("instruments")
+            .or_else(|| request.get("rics"))
+            .or_else(|| request.get("symbols"));
+        self.instruments = json_strings(instruments);
+        self.fields = match request.get("fields").and_then(|f| f.as_array()) {
+            None => Vec::new(),
+            Some(fields) => fields
+                .iter()
+                .filter_map(|f| f.get("name").unwrap_or(f).as_str())
+                .map(String::from)
+                .collect(),
+        };
+        self
+    }
+}
+
+fn json_strings(v: Option<&Value>) -> Vec<String> {
+    match v.and_then(|v| v.as_array()) {
+        None => Vec::new(),
+        Some(a) => a
+            .iter()
+            .filter_map(|s| s.as_str())
+            .map(String::from)
+            .collect(),
+    }
 }
 
 impl fmt::Display for EkError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            EkError::NoData(e) => write!(f, "No data returned: {}", e),
-            EkError::NoHeaders(e) => write!(f, "No headers returned: {}", e),
-            EkError::AuthError(e) => write!(f, "Authentication error: {}", e),
-            EkError::ConnectionError(e) => write!(f, "Connection error: {}", e),
-            EkError::ThreadError(e) => write!(f, "Thread error: {}", e),
-            EkError::DateError(e) => write!(f, "Date error: {}", e),
-            EkError::Error(e) => write!(f, "Error: {}", e),
+        let e = &self.message;
+        match self.kind {
+            ErrorKind::NoData => write!(f, "No data returned: {}", e),
+            ErrorKind::NoHeaders => write!(f, "No headers returned: {}", e),
+            ErrorKind::Auth => write!(f, "Authentication error: {}", e),
+            ErrorKind::Connection => write!(f, "Connection error: {}", e),
+            ErrorKind::Thread => write!(f, "Thread error: {}", e),
+            ErrorKind::Date => write!(f, "Date error: {}", e),
+            ErrorKind::TicketExpired => write!(f, "Ticket expired: {}", e),
+            ErrorKind::TicketTimeout => write!(f, "Ticket timed out, retry later: {}", e),
+            ErrorKind::RateLimit => write!(f, "Rate limit reached: {}", e),
+            ErrorKind::Api => write!(f, "Eikon error: {}", e),
+            ErrorKind::InvalidField => write!(f, "Invalid field: {}", e),
+            ErrorKind::Cancelled => write!(f, "Cancelled: {}", e),
+            ErrorKind::Error => write!(f, "Error: {}", e),
         }
     }
 }
 
 pub enum Fields {
-    Params(HashMap<String, HashMap<String, String>>),
+    /// Fields in request order, each with its own parameters (possibly none)
+    Params(Vec<(String, HashMap<String, String>)>),
     NoParams(Vec<String>),
 }
 
@@ -50,7 +409,13 @@ pub fn field_builder(fields: Fields) -> Value {
         Fields::NoParams(fields) => fields.iter().map(|f| json!({"name": f})).collect(),
         Fields::Params(fields) => fields
             .iter()
-            .map(|(k, v)| json!({"name": k, "parameters": v}))
+            .map(|(k, v)| {
+                if v.is_empty() {
+                    json!({"name": k})
+                } else {
+                    json!({"name": k, "parameters": v})
+                }
+            })
             .collect(),
     };
     json!(res)
@@ -73,5 +438,87 @@ mod tests {
         let answer: Value = json!([{"name": "TR.GrossProfit"}, {"name": "TR.CLOSE"}]);
         let res = field_builder(Fields::NoParams(fields));
         assert_eq!(res, answer);
+
+        let fields = vec![
+            (
+                "TR.Revenue".to_string(),
+                HashMap::from([("Period".to_string(), "FY0".to_string())]),
+            ),
+            ("TR.CLOSE".to_string(), HashMap::new()),
+        ];
+        let answer: Value = json!([
+            {"name": "TR.Revenue", "parameters": {"Period": "FY0"}},
+            {"name": "TR.CLOSE"}
+        ]);
+        assert_eq!(field_builder(Fields::Params(fields)), answer);
+    }
+
+    #[test]
+    fn test_ek_error_from_eikon() {
+        let payload = json!({"requests": [{
+            "instruments": ["AAPL.O", "MSFT.O"],
+            "fields": [{"name": "TR.CLOSE"}]
+        }]});
+        let e = EkError::from_eikon(2504, &json!("Gateway Time-out")).with_payload(&payload);
+        assert_eq!(e.kind, ErrorKind::Api);
+        assert_eq!(e.error_code, Some(2504));
+        assert_eq!(e.instruments, vec!["AAPL.O", "MSFT.O"]);
+        assert_eq!(e.fields, vec!["TR.CLOSE"]);
+        assert_eq!(e.to_string(), "Eikon error: 2504: Gateway Time-out");
+
+        let e = EkError::from_status(401);
+        assert_eq!(e.kind.condition_class(), "ek_auth_error");
+        assert_eq!(e.status, Some(401));
+    }
+
+    #[test]
+    fn test_column_from_values() {
+        let col = Column::from_values(vec![json!(1), json!(null), json!(3)], None);
+        assert_eq!(col, Column::Int(vec![Some(1), None, Some(3)]));
+
+        let col = Column::from_values(vec![json!(1), json!(2.5), json!("")], None);
+        assert_eq!(col, Column::Float(vec![Some(1.0), Some(2.5), None]));
+
+        let col = Column::from_values(vec![json!(1), json!(2)], Some(ColumnType::Float));
+        assert_eq!(col, Column::Float(vec![Some(1.0), Some(2.0)]));
+
+        let col = Column::from_values(vec![json!("2020-01-02"), json!(null)], None);
+        let date = NaiveDate::from_ymd_opt(2020, 1, 2).unwrap();
+        assert_eq!(col, Column::Date(vec![Some(date), None]));
+
+        let col = Column::from_values(vec![json!("2020-01-02T10:30:00Z")], None);
+        let datetime = date.and_hms_opt(10, 30, 0).unwrap();
+        assert_eq!(col, Column::DateTime(vec![Some(datetime)]));
+
+        let col = Column::from_values(vec![json!("AAPL.O"), json!(1)], Some(ColumnType::Int));
+        assert_eq!(col, Column::Int(vec![None, Some(1)]));
+
+        let col = Column::from_values(
+            vec![json!(1.0), json!(2), json!(null)],
+            Some(ColumnType::Int),
+        );
+        assert_eq!(col, Column::Int(vec![Some(1), Some(2), None]));
+
+        let col = Column::from_values(
+            vec![json!(1.0), json!(2.5), json!(1e300)],
+            Some(ColumnType::Int),
+        );
+        assert_eq!(col, Column::Float(vec![Some(1.0), Some(2.5), Some(1e300)]));
+
+        let col = Column::from_values(vec![json!("AAPL.O"), json!(1)], None);
+        assert_eq!(
+            col,
+            Column::Str(vec![Some("AAPL.O".to_string()), Some("1".to_string())])
+        );
+
+        let col = Column::from_values(vec![json!("a"), json!(""), json!(null)], None);
+        let expected = vec![Some("a".to_string()), Some(String::new()), None];
+        assert_eq!(col, Column::Str(expected));
+
+        let col = Column::from_values(vec![json!(""), json!("")], Some(ColumnType::Float));
+        assert_eq!(col, Column::Float(vec![None, None]));
+
+        let col = Column::from_values(vec![json!(true), json!(null)], None);
+        assert_eq!(col, Column::Bool(vec![Some(true), None]));
     }
 }